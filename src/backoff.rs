@@ -0,0 +1,614 @@
+use rand::Rng;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::config::{Config, get_int};
+use crate::data::{StateFile, from_epoch, to_epoch};
+use crate::error::CredmonError;
+
+const BACKOFF_BASE: u64 = 60;
+const BACKOFF_MAX: u64 = 3600;
+const ISSUER_FAILURE_THRESHOLD: u64 = 5;
+const ISSUER_PROBE_INTERVAL: u64 = 300;
+const QUARANTINE_FAILURES: u64 = 20;
+/// Refresh token expiry times within this many seconds are the same expiry,
+/// since issuers send a lifetime rather than a time.
+const EXPIRY_SLACK: f64 = 60.0;
+
+/// Pick a random duration in `[d/2, d]`, so that retries spread out
+/// instead of all landing on the same pass.
+fn jitter(d: Duration) -> Duration {
+    let half = d / 2;
+    half + rand::rng().random_range(Duration::ZERO..=d - half)
+}
+
+/// Exponential backoff settings.
+pub struct BackoffPolicy {
+    pub base: Duration,
+    pub max: Duration,
+}
+
+impl BackoffPolicy {
+    pub fn from_config(config: &Config) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            base: Duration::from_secs(get_int(config, "CREDMON_OAUTH_BACKOFF_BASE", BACKOFF_BASE)?),
+            max: Duration::from_secs(get_int(config, "CREDMON_OAUTH_BACKOFF_MAX", BACKOFF_MAX)?),
+        })
+    }
+
+    /// Delay before the next attempt after `failures` consecutive failures,
+    /// before jitter is applied.
+    pub fn delay(&self, failures: u32) -> Duration {
+        if failures == 0 {
+            return Duration::ZERO;
+        }
+        let factor = 1u32.checked_shl(failures - 1).unwrap_or(u32::MAX);
+        self.base.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// What a credential's retry state says about refreshing it now.
+#[derive(Debug, PartialEq)]
+pub enum Readiness {
+    Ready,
+    /// Waiting until the next attempt after a failure.
+    BackingOff(SystemTime),
+    Quarantined,
+    /// Quarantined, but the refresh token file was written since.
+    Replaced,
+}
+
+/// Retry state for a single credential.
+#[derive(Default, Debug)]
+pub struct RetryState {
+    pub consecutive_failures: u32,
+    pub next_attempt: Option<SystemTime>,
+    pub last_success: Option<SystemTime>,
+    pub last_error: Option<String>,
+    pub quarantined_at: Option<SystemTime>,
+    pub expiry_notified: Option<SystemTime>,
+}
+
+impl RetryState {
+    pub fn ready(&self, now: SystemTime) -> bool {
+        match self.next_attempt {
+            Some(t) => now >= t,
+            None => true,
+        }
+    }
+
+    /// Check whether the credential at `path` may be refreshed, without
+    /// changing anything.
+    pub fn readiness(&self, path: &Path, now: SystemTime) -> Readiness {
+        if let Some(quarantined_at) = self.quarantined_at {
+            let modified = fs::metadata(path).and_then(|x| x.modified()).ok();
+            return match modified.is_none_or(|m| m <= quarantined_at) {
+                true => Readiness::Quarantined,
+                false => Readiness::Replaced,
+            };
+        }
+        match self.next_attempt {
+            Some(t) if !self.ready(now) => Readiness::BackingOff(t),
+            _ => Readiness::Ready,
+        }
+    }
+
+    pub fn has_failures(&self) -> bool {
+        self.consecutive_failures > 0 || self.quarantined_at.is_some()
+    }
+
+    pub fn clear_failures(&mut self) {
+        self.consecutive_failures = 0;
+        self.next_attempt = None;
+        self.last_error = None;
+        self.quarantined_at = None;
+    }
+
+    pub fn record_success(&mut self, now: SystemTime) {
+        self.clear_failures();
+        self.last_success = Some(now);
+    }
+
+    pub fn record_failure(&mut self, policy: &BackoffPolicy, error: String, now: SystemTime) {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        self.next_attempt = Some(now + jitter(policy.delay(self.consecutive_failures)));
+        self.last_error = Some(error);
+    }
+}
+
+impl From<StateFile> for RetryState {
+    fn from(state: StateFile) -> Self {
+        Self {
+            consecutive_failures: state.consecutive_failures,
+            next_attempt: state.next_attempt.map(from_epoch),
+            last_success: state.last_success.map(from_epoch),
+            last_error: state.last_error,
+            quarantined_at: state.quarantined_at.map(from_epoch),
+            expiry_notified: state.expiry_notified.map(from_epoch),
+        }
+    }
+}
+
+impl From<&RetryState> for StateFile {
+    fn from(state: &RetryState) -> Self {
+        Self {
+            last_success: state.last_success.map(to_epoch),
+            last_error: state.last_error.clone(),
+            consecutive_failures: state.consecutive_failures,
+            next_attempt: state.next_attempt.map(to_epoch),
+            quarantined_at: state.quarantined_at.map(to_epoch),
+            expiry_notified: state.expiry_notified.map(to_epoch),
+        }
+    }
+}
+
+/// Circuit breaker for a single issuer.
+///
+/// After `threshold` consecutive failures the circuit opens and no
+/// refreshes are sent to the issuer until the probe time. The next
+/// refresh after that acts as a probe: success closes the circuit,
+/// failure keeps it open for another probe interval.
+#[derive(Default, Debug)]
+pub struct CircuitBreaker {
+    pub consecutive_failures: u32,
+    pub open_until: Option<SystemTime>,
+}
+
+impl CircuitBreaker {
+    pub fn allow(&self, now: SystemTime) -> bool {
+        match self.open_until {
+            Some(t) => now >= t,
+            None => true,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open_until.is_some()
+    }
+
+    pub fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+        self.open_until = None;
+    }
+
+    pub fn record_failure(&mut self, threshold: u32, probe_interval: Duration, now: SystemTime) {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        if self.consecutive_failures >= threshold {
+            self.open_until = Some(now + jitter(probe_interval));
+        }
+    }
+}
+
+/// Is this an error talking to the issuer, rather than an error
+/// specific to the credential?
+pub fn is_issuer_failure(e: &(dyn Error + 'static)) -> bool {
+    matches!(
+        e.downcast_ref::<CredmonError>(),
+        Some(CredmonError::DiscoveryError(_)) | Some(CredmonError::IssuerUnavailable(_))
+    )
+}
+
+/// Is this an error that retrying can't fix, like a revoked refresh token
+/// or an access token that fails verification?
+pub fn is_permanent_failure(e: &(dyn Error + 'static)) -> bool {
+    matches!(
+        e.downcast_ref::<CredmonError>(),
+        Some(CredmonError::RefreshTokenRevoked(_) | CredmonError::TokenVerificationError(_))
+    )
+}
+
+fn load_state<'a>(credentials: &'a mut HashMap<PathBuf, RetryState>, path: &Path) -> &'a mut RetryState {
+    credentials
+        .entry(path.to_path_buf())
+        .or_insert_with(|| match StateFile::from_file(StateFile::path_for(path)) {
+            Ok(x) => x.into(),
+            Err(_) => RetryState::default(),
+        })
+}
+
+/// Retry and circuit breaker state, kept across refresh passes.
+///
+/// Credential state is also persisted in a `.state` file next to each
+/// credential, and loaded the first time the scheduler sees it.
+pub struct RefreshScheduler {
+    policy: BackoffPolicy,
+    issuer_threshold: u32,
+    issuer_probe_interval: Duration,
+    quarantine_threshold: u32,
+    credentials: HashMap<PathBuf, RetryState>,
+    issuers: HashMap<String, CircuitBreaker>,
+}
+
+impl RefreshScheduler {
+    pub fn new(config: &Config) -> Result<Self, Box<dyn Error>> {
+        let mut ret = Self {
+            policy: BackoffPolicy::from_config(config)?,
+            issuer_threshold: 0,
+            issuer_probe_interval: Duration::ZERO,
+            quarantine_threshold: 0,
+            credentials: HashMap::new(),
+            issuers: HashMap::new(),
+        };
+        ret.update_config(config)?;
+        Ok(ret)
+    }
+
+    /// Pick up new settings after a reload, keeping existing state.
+    pub fn update_config(&mut self, config: &Config) -> Result<(), Box<dyn Error>> {
+        self.policy = BackoffPolicy::from_config(config)?;
+        self.issuer_threshold = get_int(config, "CREDMON_OAUTH_ISSUER_FAILURE_THRESHOLD", ISSUER_FAILURE_THRESHOLD)? as u32;
+        self.issuer_probe_interval = Duration::from_secs(get_int(config, "CREDMON_OAUTH_ISSUER_PROBE_INTERVAL", ISSUER_PROBE_INTERVAL)?);
+        self.quarantine_threshold = get_int(config, "CREDMON_OAUTH_QUARANTINE_FAILURES", QUARANTINE_FAILURES)? as u32;
+        Ok(())
+    }
+
+    fn load(&mut self, path: &Path) -> &mut RetryState {
+        load_state(&mut self.credentials, path)
+    }
+
+    fn save(&self, path: &Path) {
+        if let Some(state) = self.credentials.get(path) {
+            let state_path = StateFile::path_for(path);
+            if let Err(e) = StateFile::from(state).write_to_file(&state_path) {
+                log::warn!("Cannot write state file {}: {e}", state_path.to_str().unwrap());
+            }
+        }
+    }
+
+    /// Can this credential be refreshed now?
+    ///
+    /// A quarantined credential stays untouched until its refresh token
+    /// file is rewritten, e.g. by the user storing a new credential.
+    pub fn credential_ready(&mut self, path: &Path, now: SystemTime) -> bool {
+        let state = self.load(path);
+        match state.readiness(path, now) {
+            Readiness::Ready => true,
+            Readiness::BackingOff(_) => false,
+            Readiness::Quarantined => {
+                log::info!("  Credential is quarantined");
+                false
+            }
+            Readiness::Replaced => {
+                log::warn!("Credential {} was replaced, lifting quarantine", path.to_str().unwrap());
+                state.clear_failures();
+                self.save(path);
+                true
+            }
+        }
+    }
+
+    pub fn issuer_allowed(&self, issuer: &str, now: SystemTime) -> bool {
+        self.issuers.get(issuer).is_none_or(|x| x.allow(now))
+    }
+
+    pub fn retry_state(&self, path: &Path) -> Option<&RetryState> {
+        self.credentials.get(path)
+    }
+
+    /// The access token is still valid, so forget about earlier failures.
+    pub fn record_not_needed(&mut self, path: &Path) {
+        let state = self.load(path);
+        if state.has_failures() {
+            state.clear_failures();
+            self.save(path);
+        }
+    }
+
+    pub fn record_success(&mut self, path: &Path, issuer: Option<&str>, now: SystemTime) {
+        self.load(path).record_success(now);
+        self.save(path);
+        if let Some(issuer) = issuer
+            && let Some(breaker) = self.issuers.get_mut(issuer)
+        {
+            if breaker.is_open() {
+                log::warn!("Issuer {issuer} recovered, closing circuit");
+            }
+            breaker.record_success();
+        }
+    }
+
+    /// Should the user be warned that the refresh token expires at `expires_at`?
+    ///
+    /// True the first time it is asked for each expiry time.
+    pub fn notify_expiry(&mut self, path: &Path, expires_at: SystemTime) -> bool {
+        let state = self.load(path);
+        if let Some(notified) = state.expiry_notified
+            && (to_epoch(notified) - to_epoch(expires_at)).abs() < EXPIRY_SLACK
+        {
+            return false;
+        }
+        state.expiry_notified = Some(expires_at);
+        self.save(path);
+        true
+    }
+
+    pub fn record_failure(&mut self, path: &Path, issuer: Option<&str>, e: &(dyn Error + 'static), now: SystemTime) {
+        let issuer_failure = is_issuer_failure(e);
+        let permanent_failure = is_permanent_failure(e);
+        let quarantine_threshold = self.quarantine_threshold;
+        let state = load_state(&mut self.credentials, path);
+        state.record_failure(&self.policy, e.to_string(), now);
+        log::info!(
+            "  {} consecutive failures, next attempt in {}s",
+            state.consecutive_failures,
+            state.next_attempt.unwrap().duration_since(now).unwrap_or_default().as_secs()
+        );
+        if permanent_failure && quarantine_threshold > 0 {
+            log::warn!("Credential {} cannot be refreshed any more, quarantining", path.to_str().unwrap());
+            state.quarantined_at = Some(now);
+        } else if !issuer_failure && quarantine_threshold > 0 && state.consecutive_failures >= quarantine_threshold {
+            log::warn!(
+                "Credential {} failed {} times in a row, quarantining",
+                path.to_str().unwrap(),
+                state.consecutive_failures
+            );
+            state.quarantined_at = Some(now);
+        }
+        self.save(path);
+
+        if let Some(issuer) = issuer
+            && issuer_failure
+        {
+            let breaker = self.issuers.entry(issuer.to_string()).or_default();
+            let was_open = breaker.is_open();
+            breaker.record_failure(self.issuer_threshold, self.issuer_probe_interval, now);
+            if breaker.is_open() && !was_open {
+                log::warn!("Issuer {issuer} failed {} times in a row, pausing refreshes", breaker.consecutive_failures);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::test_logger;
+    use tempfile::tempdir;
+
+    fn policy() -> BackoffPolicy {
+        BackoffPolicy {
+            base: Duration::from_secs(10),
+            max: Duration::from_secs(100),
+        }
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        test_logger();
+        let p = policy();
+        assert_eq!(p.delay(0), Duration::ZERO);
+        assert_eq!(p.delay(1), Duration::from_secs(10));
+        assert_eq!(p.delay(2), Duration::from_secs(20));
+        assert_eq!(p.delay(4), Duration::from_secs(80));
+        assert_eq!(p.delay(5), Duration::from_secs(100));
+        assert_eq!(p.delay(64), Duration::from_secs(100));
+    }
+
+    #[test]
+    fn test_backoff_config() {
+        test_logger();
+        let mut config = Config::new();
+        let p = BackoffPolicy::from_config(&config).unwrap();
+        assert_eq!(p.base, Duration::from_secs(BACKOFF_BASE));
+        assert_eq!(p.max, Duration::from_secs(BACKOFF_MAX));
+
+        config.insert("CREDMON_OAUTH_BACKOFF_BASE".into(), "5".into());
+        config.insert("CREDMON_OAUTH_BACKOFF_MAX".into(), 50.into());
+        let p = BackoffPolicy::from_config(&config).unwrap();
+        assert_eq!(p.base, Duration::from_secs(5));
+        assert_eq!(p.max, Duration::from_secs(50));
+    }
+
+    #[test]
+    fn test_retry_state() {
+        test_logger();
+        let p = policy();
+        let now = SystemTime::now();
+        let mut state = RetryState::default();
+        assert!(state.ready(now));
+
+        state.record_failure(&p, "err".into(), now);
+        assert_eq!(state.consecutive_failures, 1);
+        assert_eq!(state.last_error, Some("err".into()));
+        assert!(!state.ready(now));
+        assert!(!state.ready(now + Duration::from_secs(4)));
+        assert!(state.ready(now + Duration::from_secs(10)));
+
+        state.record_failure(&p, "err".into(), now);
+        assert!(!state.ready(now + Duration::from_secs(9)));
+        assert!(state.ready(now + Duration::from_secs(20)));
+
+        state.record_success(now);
+        assert_eq!(state.consecutive_failures, 0);
+        assert_eq!(state.last_error, None);
+        assert_eq!(state.last_success, Some(now));
+        assert!(state.ready(now));
+    }
+
+    #[test]
+    fn test_readiness() {
+        test_logger();
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("provider.top");
+        fs::write(&path, "{}").unwrap();
+        let now = SystemTime::now();
+
+        let mut state = RetryState::default();
+        assert_eq!(state.readiness(&path, now), Readiness::Ready);
+
+        state.record_failure(&policy(), "err".into(), now);
+        let next = state.next_attempt.unwrap();
+        assert_eq!(state.readiness(&path, now), Readiness::BackingOff(next));
+        assert_eq!(state.readiness(&path, next), Readiness::Ready);
+
+        state.quarantined_at = Some(now + Duration::from_secs(10));
+        assert_eq!(state.readiness(&path, next), Readiness::Quarantined);
+        state.quarantined_at = Some(now - Duration::from_secs(10));
+        assert_eq!(state.readiness(&path, now), Readiness::Replaced);
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        test_logger();
+        let now = SystemTime::now();
+        let probe = Duration::from_secs(60);
+        let mut breaker = CircuitBreaker::default();
+
+        breaker.record_failure(3, probe, now);
+        breaker.record_failure(3, probe, now);
+        assert!(!breaker.is_open());
+        assert!(breaker.allow(now));
+
+        breaker.record_failure(3, probe, now);
+        assert!(breaker.is_open());
+        assert!(!breaker.allow(now));
+        assert!(breaker.allow(now + probe));
+
+        // failed probe keeps it open
+        let later = now + probe;
+        breaker.record_failure(3, probe, later);
+        assert!(!breaker.allow(later));
+
+        breaker.record_success();
+        assert!(!breaker.is_open());
+        assert!(breaker.allow(later));
+    }
+
+    #[test]
+    fn test_scheduler() {
+        test_logger();
+        let tmp_dir = tempdir().unwrap();
+        let mut config = Config::new();
+        config.insert("CREDMON_OAUTH_ISSUER_FAILURE_THRESHOLD".into(), 2.into());
+        let mut scheduler = RefreshScheduler::new(&config).unwrap();
+
+        let now = SystemTime::now();
+        let path1 = tmp_dir.path().join("provider1.top");
+        let path2 = tmp_dir.path().join("provider2.top");
+        let issuer = "https://issuer";
+
+        // credential errors do not count against the issuer
+        let err = CredmonError::RequestError("invalid_grant".into());
+        scheduler.record_failure(&path1, Some(issuer), &err, now);
+        scheduler.record_failure(&path2, Some(issuer), &err, now);
+        assert!(!scheduler.credential_ready(&path1, now));
+        assert!(scheduler.issuer_allowed(issuer, now));
+
+        let err = CredmonError::IssuerUnavailable("503".into());
+        scheduler.record_failure(&path1, Some(issuer), &err, now);
+        scheduler.record_failure(&path2, Some(issuer), &err, now);
+        assert!(!scheduler.issuer_allowed(issuer, now));
+        assert!(scheduler.issuer_allowed("https://other", now));
+
+        scheduler.record_success(&path1, Some(issuer), now);
+        assert!(scheduler.credential_ready(&path1, now));
+        assert!(scheduler.issuer_allowed(issuer, now));
+        assert!(!scheduler.credential_ready(&path2, now));
+        assert_eq!(scheduler.retry_state(&path2).unwrap().consecutive_failures, 2);
+    }
+
+    #[test]
+    fn test_scheduler_persistence() {
+        test_logger();
+        let tmp_dir = tempdir().unwrap();
+        let config = Config::new();
+        let path = tmp_dir.path().join("provider.top");
+        let now = SystemTime::now();
+
+        let mut scheduler = RefreshScheduler::new(&config).unwrap();
+        let err = CredmonError::RequestError("invalid_grant".into());
+        scheduler.record_failure(&path, None, &err, now);
+
+        let state = StateFile::from_file(StateFile::path_for(&path)).unwrap();
+        assert_eq!(state.consecutive_failures, 1);
+        assert_eq!(state.last_error, Some(err.to_string()));
+
+        // a restarted scheduler picks up where the old one left off
+        let mut scheduler = RefreshScheduler::new(&config).unwrap();
+        assert!(!scheduler.credential_ready(&path, now));
+        assert_eq!(scheduler.retry_state(&path).unwrap().consecutive_failures, 1);
+
+        scheduler.record_not_needed(&path);
+        let state = StateFile::from_file(StateFile::path_for(&path)).unwrap();
+        assert_eq!(state.consecutive_failures, 0);
+    }
+
+    #[test]
+    fn test_scheduler_quarantine() {
+        test_logger();
+        let tmp_dir = tempdir().unwrap();
+        let mut config = Config::new();
+        config.insert("CREDMON_OAUTH_QUARANTINE_FAILURES".into(), 2.into());
+        let path = tmp_dir.path().join("provider.top");
+        fs::write(&path, "{}").unwrap();
+        let now = SystemTime::now() + Duration::from_secs(10);
+        let later = now + Duration::from_secs(BACKOFF_MAX * 2);
+
+        let mut scheduler = RefreshScheduler::new(&config).unwrap();
+        let err = CredmonError::RequestError("invalid_grant".into());
+        scheduler.record_failure(&path, None, &err, now);
+        scheduler.record_failure(&path, None, &err, now);
+        assert!(scheduler.retry_state(&path).unwrap().quarantined_at.is_some());
+        assert!(!scheduler.credential_ready(&path, later));
+
+        // storing a new credential lifts the quarantine
+        let f = fs::File::options().write(true).open(&path).unwrap();
+        f.set_modified(later).unwrap();
+        assert!(scheduler.credential_ready(&path, later));
+        assert_eq!(scheduler.retry_state(&path).unwrap().consecutive_failures, 0);
+    }
+
+    #[test]
+    fn test_scheduler_quarantine_revoked() {
+        test_logger();
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("provider.top");
+        fs::write(&path, "{}").unwrap();
+        let now = SystemTime::now() + Duration::from_secs(10);
+
+        let mut scheduler = RefreshScheduler::new(&Config::new()).unwrap();
+        let err = CredmonError::RefreshTokenRevoked("invalid_grant".into());
+        scheduler.record_failure(&path, None, &err, now);
+        assert!(scheduler.retry_state(&path).unwrap().quarantined_at.is_some());
+
+        // so is a token that fails verification, with that as the reason
+        let path = tmp_dir.path().join("verify.top");
+        let verify_err = CredmonError::TokenVerificationError("subject does not match".into());
+        scheduler.record_failure(&path, None, &verify_err, now);
+        let state = scheduler.retry_state(&path).unwrap();
+        assert!(state.quarantined_at.is_some());
+        assert!(state.last_error.as_ref().unwrap().contains("subject does not match"));
+
+        // unless quarantine is turned off
+        let mut config = Config::new();
+        config.insert("CREDMON_OAUTH_QUARANTINE_FAILURES".into(), 0.into());
+        let path = tmp_dir.path().join("other.top");
+        let mut scheduler = RefreshScheduler::new(&config).unwrap();
+        scheduler.record_failure(&path, None, &err, now);
+        assert!(scheduler.retry_state(&path).unwrap().quarantined_at.is_none());
+    }
+
+    #[test]
+    fn test_scheduler_notify_expiry() {
+        test_logger();
+        let tmp_dir = tempdir().unwrap();
+        let config = Config::new();
+        let path = tmp_dir.path().join("provider.top");
+        let expires_at = SystemTime::now() + Duration::from_secs(86400);
+
+        let mut scheduler = RefreshScheduler::new(&config).unwrap();
+        assert!(scheduler.notify_expiry(&path, expires_at));
+        assert!(!scheduler.notify_expiry(&path, expires_at + Duration::from_secs(2)));
+
+        // remembered across restarts, and failures don't forget it
+        let mut scheduler = RefreshScheduler::new(&config).unwrap();
+        scheduler.record_failure(&path, None, &CredmonError::RequestError("invalid_grant".into()), SystemTime::now());
+        assert!(!scheduler.notify_expiry(&path, expires_at));
+
+        // a new refresh token with a new expiry
+        assert!(scheduler.notify_expiry(&path, expires_at + Duration::from_secs(86400)));
+    }
+}
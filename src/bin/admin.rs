@@ -0,0 +1,141 @@
+use std::backtrace::Backtrace;
+use std::env;
+use std::error::Error;
+use std::process::ExitCode;
+use std::time::SystemTime;
+
+use condor_credmon::backoff::RefreshScheduler;
+use condor_credmon::config::{config as condor_config, set_credmon_config};
+use condor_credmon::error::CredmonError;
+use condor_credmon::local_issuer::LocalIssuer;
+use condor_credmon::logging::{configure_debug_logging, configure_logging};
+use condor_credmon::refresh::{credential_dir, force_refresh, refresh_expiry_warning};
+use condor_credmon::status::{collect_status, format_table};
+
+const USAGE: &str = "usage: condor_credmon_rust_admin [-local-name <name>] status [--json]
+       condor_credmon_rust_admin [-local-name <name>] expiring [--within <seconds>] [--json]
+       condor_credmon_rust_admin [-local-name <name>] refresh <user> <provider[_handle]>
+       condor_credmon_rust_admin [-local-name <name>] jwks <provider>";
+
+fn status(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut json = false;
+    for arg in args {
+        match arg.as_str() {
+            "--json" => json = true,
+            _ => return Err(Box::new(CredmonError::ArgumentError(format!("unknown argument {arg}\n{USAGE}")))),
+        }
+    }
+
+    let config = condor_config();
+    let statuses = collect_status(&credential_dir(&config)?, &config)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&statuses)?);
+    } else {
+        print!("{}", format_table(&statuses, SystemTime::now()));
+    }
+    Ok(())
+}
+
+/// List credentials whose refresh tokens expire soon.
+fn expiring(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let config = condor_config();
+    let mut json = false;
+    let mut within = refresh_expiry_warning(&config)?;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--within" => {
+                let value = iter.next().ok_or(CredmonError::ArgumentError(USAGE.into()))?;
+                within = value.parse().map_err(|_| CredmonError::ArgumentError(format!("bad --within {value}")))?;
+            }
+            _ => return Err(Box::new(CredmonError::ArgumentError(format!("unknown argument {arg}\n{USAGE}")))),
+        }
+    }
+
+    let now = SystemTime::now();
+    let cutoff = now.duration_since(SystemTime::UNIX_EPOCH)?.as_secs_f64() + within as f64;
+    let statuses: Vec<_> = collect_status(&credential_dir(&config)?, &config)?
+        .into_iter()
+        .filter(|x| x.refresh_expires_at.is_some_and(|t| t <= cutoff))
+        .collect();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&statuses)?);
+    } else {
+        print!("{}", format_table(&statuses, now));
+    }
+    Ok(())
+}
+
+fn refresh(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let [user, name] = args else {
+        return Err(Box::new(CredmonError::ArgumentError(USAGE.into())));
+    };
+    if user.contains('/') || name.contains('/') {
+        return Err(Box::new(CredmonError::ArgumentError("user and provider cannot contain '/'".into())));
+    }
+
+    let config = condor_config();
+    let path = credential_dir(&config)?.join(user).join(format!("{name}.top"));
+    if !path.exists() {
+        return Err(Box::new(CredmonError::ArgumentError(format!("no credential at {}", path.to_str().unwrap()))));
+    }
+
+    let mut scheduler = RefreshScheduler::new(&config)?;
+    force_refresh(&path, &mut scheduler)?;
+    log::warn!("Refreshed {}", path.to_str().unwrap());
+    Ok(())
+}
+
+/// Print the public keys of a local issuer, to publish as its `jwks_uri`.
+fn jwks(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let [provider] = args else {
+        return Err(Box::new(CredmonError::ArgumentError(USAGE.into())));
+    };
+
+    let issuer = LocalIssuer::from_config(provider, &condor_config())?;
+    println!("{}", serde_json::to_string_pretty(&issuer.jwks()?)?);
+    Ok(())
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let mut argv: Vec<String> = env::args().collect();
+
+    // look at the same config as a credmon started with -local-name
+    let mut local_name = None;
+    if argv.get(1).map(|x| x.as_str()) == Some("-local-name") {
+        if argv.len() < 3 {
+            return Err(Box::new(CredmonError::ArgumentError(USAGE.into())));
+        }
+        local_name = Some(argv.remove(2));
+        argv.remove(1);
+    }
+    set_credmon_config(local_name, None);
+
+    // forced refreshes are for debugging, so always be verbose
+    let _log_handle = match argv.get(1).map(|x| x.as_str()) {
+        Some("refresh") => configure_debug_logging()?,
+        _ => configure_logging(Some("stderr"))?,
+    };
+
+    match argv.get(1).map(|x| x.as_str()) {
+        Some("status") => status(&argv[2..]),
+        Some("expiring") => expiring(&argv[2..]),
+        Some("refresh") => refresh(&argv[2..]),
+        Some("jwks") => jwks(&argv[2..]),
+        _ => Err(Box::new(CredmonError::ArgumentError(USAGE.into()))),
+    }
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(e) => {
+            log::info!("Backtrace: {}", Backtrace::force_capture());
+            log::error!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -1,68 +1,69 @@
 use nix::unistd::{Uid, User};
 use std::backtrace::Backtrace;
+use std::env;
 use std::error::Error;
-use std::path::PathBuf;
 use std::process::ExitCode;
 
-use condor_credmon::config::config as condor_config;
-use condor_credmon::data::{Args, RefreshFile, compare_scopes, write_tokens_to_file};
+use condor_credmon::config::storer_config;
+use condor_credmon::data::Args;
 use condor_credmon::error::CredmonError;
-use condor_credmon::exchange::do_token_exchange;
 use condor_credmon::logging::configure_logging;
-use condor_credmon::refresh::should_refresh;
+use condor_credmon::privileges::{check_context, drop_privileges, is_setuid, sanitize_env};
+use condor_credmon::socket::{send_request, socket_path};
+use condor_credmon::store::store_credential;
 
 fn run() -> Result<(), Box<dyn Error>> {
+    // only root work (reading client secrets, writing credentials) is done
+    // with root privileges, the rest runs as the calling user
+    let root = Uid::effective().is_root();
+    drop_privileges()?;
+
     let _log_handle = configure_logging(Some("stderr"))?;
     let args = Args::from_env()?;
-    let config = condor_config();
-
-    let username = User::from_uid(Uid::current())?
-        .ok_or(CredmonError::GenericError("Cannot get username".into()))?
-        .name;
-
-    let mut refresh_filename = args.provider.clone();
-    if let Some(ref handle) = args.handle {
-        refresh_filename += "_";
-        refresh_filename += handle;
-        log::warn!("Creating token for {username} with provider {} and handle {handle}", args.provider);
-    } else {
-        log::warn!("Creating token for {username} with provider {} and no handle", args.provider);
-    }
-    refresh_filename += ".top";
-
-    let cred_dir = config
-        .get("SEC_CREDENTIAL_DIRECTORY_OAUTH")
-        .ok_or(CredmonError::OAuthDirError("missing SEC_CREDENTIAL_DIRECTORY_OAUTH in config".into()))?
-        .as_str()
-        .ok_or(CredmonError::OAuthDirError("SEC_CREDENTIAL_DIRECTORY_OAUTH is not a string".into()))?;
-
-    let path = PathBuf::from(cred_dir).join(username.as_str()).join(refresh_filename);
+    // resolved like the credmon serving this provider does, so both
+    // agree on where its credentials live
+    let config = storer_config(&args.provider);
 
-    // check if the token already exists and matches the request
-    let create_token = match RefreshFile::from_file(&path) {
-        Ok(rf) => {
-            if !compare_scopes(args.scopes.as_str(), rf.scopes.as_str()) {
-                log::info!("Scopes of existing token do not match. Making new token!");
-                true
-            } else {
-                // check access token and expiration
-                should_refresh(&path).unwrap_or(true)
-            }
+    // a credmon serving a socket stores credentials for us
+    if let Some(socket) = socket_path(&config)? {
+        log::info!("Sending request to credmon at {}", socket.to_str().unwrap_or_default());
+        let request = env::args().nth(1).unwrap_or_default();
+        let response = send_request(&socket, &request)?;
+        if !response.ok {
+            return Err(Box::new(CredmonError::RequestError(response.message)));
         }
-        Err(_) => true,
-    };
+        log::warn!("{}", response.message);
+        return Ok(());
+    }
 
-    if create_token {
-        let result = do_token_exchange(&args, username.as_str())?;
-        write_tokens_to_file(&path, result)?;
-    } else {
-        log::warn!("Token already exists, not contacting server");
+    // without root we could only fail with permission errors
+    if !root {
+        return Err(Box::new(CredmonError::ConfigError(format!(
+            "CREDMON_OAUTH_SOCKET is not configured for provider {}, and the client is not setuid root",
+            args.provider
+        ))));
     }
 
+    // the real uid is the user who ran us, even when setuid
+    let username = User::from_uid(Uid::current())?
+        .ok_or(CredmonError::GenericError("Cannot get username".into()))?
+        .name;
+
+    store_credential(&args, &username, &config)?;
     Ok(())
 }
 
 fn main() -> ExitCode {
+    // before anything else, so nothing runs with the caller's environment
+    if is_setuid() {
+        sanitize_env();
+    }
+    let argv: Vec<String> = env::args().collect();
+    if let Err(e) = check_context(&argv) {
+        eprintln!("Error creating token: {e}");
+        return ExitCode::FAILURE;
+    }
+
     match run() {
         Ok(_) => ExitCode::SUCCESS,
         Err(e) => {
@@ -3,18 +3,25 @@ use signal_hook::consts::SIGHUP;
 use signal_hook::iterator::Signals;
 use std::backtrace::Backtrace;
 use std::error::Error;
-use std::process::ExitCode;
+use std::fs;
+use std::process::{self, ExitCode};
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::Relaxed;
 use std::thread;
 use std::thread::sleep;
 use std::time::{Duration, SystemTime};
 
-use condor_credmon::config::{Config, coerce_to_int, config as condor_config, reload_config};
+use condor_credmon::backoff::RefreshScheduler;
+use condor_credmon::config::{Config, coerce_to_int, config as condor_config, reload_config, set_credmon_config};
+use condor_credmon::data::DaemonArgs;
 use condor_credmon::logging::{configure_logging, update_file_logging};
-use condor_credmon::refresh::refresh_all_tokens;
+use condor_credmon::refresh::{PlannedAction, RefreshSummary, credential_dir, list_marked_users, plan_all_tokens, refresh_all_tokens};
+use condor_credmon::secret_file::flush_secret_cache;
+use condor_credmon::socket::{serve, socket_path};
+use condor_credmon::status::format_duration;
 
 const TOKEN_REFRESH_INTERVAL: u64 = 60;
+const EXIT_REFRESH_FAILED: u8 = 2;
 
 fn get_refresh_interval(config: &Config) -> Result<u64, Box<dyn Error>> {
     match config.get("CREDMON_OAUTH_TOKEN_REFRESH") {
@@ -26,8 +33,79 @@ fn get_refresh_interval(config: &Config) -> Result<u64, Box<dyn Error>> {
     }
 }
 
-fn run() -> Result<(), Box<dyn Error>> {
-    let mut log_handle = configure_logging(None)?;
+/// Report what a refresh pass would do, without doing it.
+fn dry_run() -> Result<(), Box<dyn Error>> {
+    let config = condor_config();
+    let now = SystemTime::now();
+
+    for (path, action) in plan_all_tokens()? {
+        let path = path.to_str().unwrap();
+        match action {
+            PlannedAction::Refresh => println!("refresh          {path}"),
+            PlannedAction::NotNeeded => println!("ok               {path}"),
+            PlannedAction::BackingOff(t) => {
+                let wait = t - now.duration_since(SystemTime::UNIX_EPOCH)?.as_secs_f64();
+                println!("backing off      {path} (next attempt in {})", format_duration(wait));
+            }
+            PlannedAction::Quarantined => println!("quarantined      {path}"),
+            PlannedAction::RefreshTokenExpired => println!("expired          {path}"),
+            PlannedAction::ConfigProblem(e) => println!("config problem   {path}: {e}"),
+        }
+    }
+
+    for marked in list_marked_users(&credential_dir(&config)?, &config)? {
+        match marked.sweep_at.duration_since(now) {
+            Ok(wait) => println!("marked           {} (sweep in {})", marked.user, format_duration(wait.as_secs_f64())),
+            Err(_) => println!("sweep            {}", marked.user),
+        }
+    }
+
+    Ok(())
+}
+
+/// Exit code for `--once`: success only if nothing failed.
+fn once_exit_code(summary: Option<RefreshSummary>) -> ExitCode {
+    match summary {
+        Some(x) => {
+            warn!(
+                "Checked {} credentials: {} refreshed, {} skipped, {} failed, {} users swept, {} refresh tokens expiring",
+                x.checked, x.refreshed, x.skipped, x.failed, x.swept, x.expiring
+            );
+            if x.failed > 0 {
+                ExitCode::from(EXIT_REFRESH_FAILED)
+            } else {
+                ExitCode::SUCCESS
+            }
+        }
+        None => ExitCode::FAILURE,
+    }
+}
+
+fn run() -> Result<ExitCode, Box<dyn Error>> {
+    // logging is not set up yet, so report bad arguments directly
+    let args = match DaemonArgs::from_env() {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{e}");
+            return Ok(ExitCode::FAILURE);
+        }
+    };
+    set_credmon_config(args.local_name.clone(), args.config_file.clone());
+
+    if args.dry_run {
+        let _log_handle = configure_logging(Some("stderr"))?;
+        dry_run()?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let mut log_handle = configure_logging(if args.stderr { Some("stderr") } else { None })?;
+    for flag in &args.ignored {
+        log::info!("Ignoring DaemonCore argument {flag}");
+    }
+
+    if let Some(pidfile) = &args.pidfile {
+        fs::write(pidfile, format!("{}\n", process::id()))?;
+    }
 
     static RELOAD: AtomicBool = AtomicBool::new(false);
     let mut signals = Signals::new([SIGHUP])?;
@@ -40,28 +118,45 @@ fn run() -> Result<(), Box<dyn Error>> {
     });
 
     let mut config = condor_config();
+    if !args.once
+        && let Some(socket) = socket_path(&config)?
+    {
+        serve(&socket)?;
+    }
     let mut refresh_interval = get_refresh_interval(&config)?;
+    let mut scheduler = RefreshScheduler::new(&config)?;
     let mut last_refresh = SystemTime::UNIX_EPOCH;
 
     loop {
         let now = SystemTime::now();
         if now.duration_since(last_refresh).unwrap().as_secs() > refresh_interval {
             log::info!("Checking for tokens to refresh");
-            match refresh_all_tokens() {
-                Ok(_) => {}
-                Err(e) => warn!("Error refreshing: {e}"),
+            let summary = match refresh_all_tokens(&mut scheduler) {
+                Ok(x) => Some(x),
+                Err(e) => {
+                    warn!("Error refreshing: {e}");
+                    None
+                }
             };
             log::info!("Done refreshing tokens");
             last_refresh = now;
+
+            if args.once {
+                return Ok(once_exit_code(summary));
+            }
         }
 
         sleep(Duration::from_millis(100));
         if RELOAD.load(Relaxed) {
             RELOAD.store(false, Relaxed);
             reload_config();
-            update_file_logging(&mut log_handle)?;
+            flush_secret_cache();
+            if !args.stderr {
+                update_file_logging(&mut log_handle)?;
+            }
             config = condor_config();
             refresh_interval = get_refresh_interval(&config)?;
+            scheduler.update_config(&config)?;
             last_refresh = SystemTime::UNIX_EPOCH; // refresh immediately after reload
         }
     }
@@ -69,7 +164,7 @@ fn run() -> Result<(), Box<dyn Error>> {
 
 fn main() -> ExitCode {
     match run() {
-        Ok(_) => ExitCode::SUCCESS,
+        Ok(x) => x,
         Err(e) => {
             log::error!("Backtrace: {}", Backtrace::force_capture());
             log::error!("Fatal error in credmon: {e}");
@@ -1,6 +1,9 @@
 use memoize::memoize;
 use serde_json::{Map, Value};
+use std::fs;
+use std::path::PathBuf;
 use std::process::Command;
+use std::sync::RwLock;
 
 use crate::error::CredmonError;
 
@@ -18,6 +21,165 @@ pub fn coerce_to_int(val: &Value) -> Result<u64, Box<dyn std::error::Error>> {
     }
 }
 
+/// Get an optional string setting.
+pub fn get_str<'a>(config: &'a Config, key: &str) -> Result<Option<&'a str>, CredmonError> {
+    match config.get(key) {
+        None => Ok(None),
+        Some(x) => x.as_str().map(Some).ok_or(CredmonError::ConfigError(format!("{key} is not a string"))),
+    }
+}
+
+/// Get an integer setting, or `default` if it is not set.
+pub fn get_int(config: &Config, key: &str, default: u64) -> Result<u64, Box<dyn std::error::Error>> {
+    match config.get(key) {
+        Some(x) => coerce_to_int(x),
+        None => Ok(default),
+    }
+}
+
+/// Like HTCondor, accept true/false, yes/no, and 1/0 in any case.
+pub fn coerce_to_bool(val: &Value) -> Result<bool, Box<dyn std::error::Error>> {
+    if let Some(x) = val.as_bool() {
+        return Ok(x);
+    }
+    let s = match val.as_str() {
+        Some(x) => x.to_ascii_lowercase(),
+        None => val.to_string(),
+    };
+    match s.trim() {
+        "true" | "yes" | "1" => Ok(true),
+        "false" | "no" | "0" => Ok(false),
+        _ => Err(Box::new(CredmonError::ConfigError("not a boolean".into()))),
+    }
+}
+
+/// The subsystem name condor_master uses for the credmon.
+pub const CREDMON_SUBSYSTEM: &str = "CREDMON_OAUTH";
+
+/// Options changing how the config is resolved, from the command line.
+#[derive(Default, Clone, Debug)]
+pub struct ConfigOptions {
+    /// Prefer `<subsystem>.`-prefixed knobs.
+    pub subsystem: Option<String>,
+    /// Prefer `<local_name>.`-prefixed knobs, like HTCondor's `-local-name`.
+    pub local_name: Option<String>,
+    /// A file of `KEY = VALUE` lines that override the HTCondor config.
+    pub override_file: Option<PathBuf>,
+}
+
+static CONFIG_OPTIONS: RwLock<Option<ConfigOptions>> = RwLock::new(None);
+
+/// Set the config options, and drop any config loaded without them.
+pub fn set_config_options(options: ConfigOptions) {
+    *CONFIG_OPTIONS.write().unwrap() = Some(options);
+    reload_config();
+}
+
+/// Resolve the config the way the credmon daemon sees it, with the
+/// subsystem prefix and an optional local name.
+///
+/// Every binary goes through this, so the storer and the credmon agree on
+/// knobs like `SEC_CREDENTIAL_DIRECTORY_OAUTH`.
+pub fn set_credmon_config(local_name: Option<String>, override_file: Option<PathBuf>) {
+    set_config_options(ConfigOptions {
+        subsystem: Some(CREDMON_SUBSYSTEM.into()),
+        local_name,
+        override_file,
+    });
+}
+
+/// The local name of the credmon serving a provider, from `<provider>_LOCAL_NAME`.
+pub fn provider_local_name(provider: &str, config: &Config) -> Option<String> {
+    config.get(&format!("{provider}_LOCAL_NAME")).and_then(|x| x.as_str()).map(String::from)
+}
+
+/// The config to store a credential for `provider` with: the credmon's,
+/// as seen by the credmon serving that provider.
+pub fn storer_config(provider: &str) -> Config {
+    set_credmon_config(None, None);
+    let config = config();
+    match provider_local_name(provider, &config) {
+        Some(local_name) => {
+            log::info!("Using config for local name {local_name}");
+            set_credmon_config(Some(local_name), None);
+            self::config()
+        }
+        None => config,
+    }
+}
+
+/// Parse a simple config file of `KEY = VALUE` lines.
+///
+/// Blank lines and `#` comments are skipped. There is no macro expansion.
+pub fn parse_config_file(contents: &str) -> Result<Config, Box<dyn std::error::Error>> {
+    let mut ret = Config::new();
+    for (num, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((key, val)) if !key.trim().is_empty() => {
+                ret.insert(key.trim().to_string(), val.trim().into());
+            }
+            _ => return Err(Box::new(CredmonError::ConfigError(format!("bad config line {}: {line}", num + 1)))),
+        }
+    }
+    Ok(ret)
+}
+
+/// Insert a value, replacing any key that differs only by case.
+///
+/// The existing key keeps its spelling, since code looks knobs up by
+/// their usual upper case names.
+fn insert_knob(config: &mut Config, key: &str, val: Value) {
+    let key = config.keys().find(|k| k.eq_ignore_ascii_case(key)).cloned().unwrap_or_else(|| key.to_string());
+    config.insert(key, val);
+}
+
+/// Overlay `<prefix>.KEY` entries onto `KEY`.
+pub fn apply_prefix(config: &mut Config, prefix: &str) {
+    let prefix = format!("{prefix}.");
+    let overrides: Vec<(String, Value)> = config
+        .iter()
+        .filter(|(k, _)| k.len() > prefix.len() && k[..prefix.len()].eq_ignore_ascii_case(&prefix))
+        .map(|(k, v)| (k[prefix.len()..].to_string(), v.clone()))
+        .collect();
+    for (key, val) in overrides {
+        insert_knob(config, &key, val);
+    }
+}
+
+/// Resolve prefixed knobs the way HTCondor does, so that for a knob
+/// `KEY` the first of these that is set wins:
+///
+/// * `<subsystem>.<local_name>.KEY`
+/// * `<local_name>.KEY`
+/// * `<subsystem>.KEY`
+/// * `KEY`
+pub fn apply_local_name(config: &mut Config, subsystem: Option<&str>, local_name: Option<&str>) {
+    if let Some(subsystem) = subsystem {
+        apply_prefix(config, subsystem);
+    }
+    if let Some(local_name) = local_name {
+        apply_prefix(config, local_name);
+        if let Some(subsystem) = subsystem {
+            apply_prefix(config, &format!("{subsystem}.{local_name}"));
+        }
+    }
+}
+
+/// Apply the command line config options to a loaded config.
+pub fn apply_config_options(config: &mut Config, options: &ConfigOptions) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(path) = &options.override_file {
+        for (key, val) in parse_config_file(&fs::read_to_string(path)?)? {
+            insert_knob(config, &key, val);
+        }
+    }
+    apply_local_name(config, options.subsystem.as_deref(), options.local_name.as_deref());
+    Ok(())
+}
+
 #[memoize]
 pub fn config() -> Config {
     log::info!(target:"config", "Loading HTCondor config");
@@ -37,7 +199,12 @@ pub fn config() -> Config {
 
     // Convert stdout to a String
     let json_output = String::from_utf8(output.stdout).expect("Cannot decode HTCondor config!");
-    serde_json::from_str(&json_output).expect("Cannot decode HTCondor config!")
+    let mut config: Config = serde_json::from_str(&json_output).expect("Cannot decode HTCondor config!");
+
+    if let Some(options) = CONFIG_OPTIONS.read().unwrap().as_ref() {
+        apply_config_options(&mut config, options).expect("Cannot apply config options!");
+    }
+    config
 }
 
 pub fn reload_config() {
@@ -59,4 +226,96 @@ mod tests {
         assert_eq!(coerce_to_int(config.get("bar").unwrap()).unwrap(), 20);
         assert!(coerce_to_int(config.get("baz").unwrap()).is_err());
     }
+
+    #[test]
+    fn test_coerce_to_bool() {
+        assert!(coerce_to_bool(&"True".into()).unwrap());
+        assert!(coerce_to_bool(&"yes".into()).unwrap());
+        assert!(coerce_to_bool(&true.into()).unwrap());
+        assert!(coerce_to_bool(&1.into()).unwrap());
+        assert!(!coerce_to_bool(&"FALSE".into()).unwrap());
+        assert!(!coerce_to_bool(&0.into()).unwrap());
+        assert!(coerce_to_bool(&"maybe".into()).is_err());
+    }
+
+    #[test]
+    fn test_parse_config_file() {
+        let ret = parse_config_file("# comment\n\nFOO = bar baz\n  BAR=10  \n").unwrap();
+        assert_eq!(ret.get("FOO").unwrap(), "bar baz");
+        assert_eq!(coerce_to_int(ret.get("BAR").unwrap()).unwrap(), 10);
+
+        assert!(parse_config_file("FOO").is_err());
+        assert!(parse_config_file(" = bar").is_err());
+    }
+
+    #[test]
+    fn test_provider_local_name() {
+        let mut config: Config = Map::new();
+        config.insert("CREDMON_OAUTH.second_LOCAL_NAME".into(), "SECOND".into());
+        assert_eq!(provider_local_name("second", &config), None);
+
+        // the subsystem prefix applies to the storer as it does to the credmon
+        apply_local_name(&mut config, Some(CREDMON_SUBSYSTEM), None);
+        assert_eq!(provider_local_name("second", &config), Some("SECOND".into()));
+    }
+
+    #[test]
+    fn test_apply_local_name() {
+        let mut config: Config = Map::new();
+        config.insert("CREDMON_OAUTH_LOG".into(), "/var/log/condor/CredMonOAuthLog".into());
+        config.insert("second.CREDMON_OAUTH_LOG".into(), "/var/log/condor/SecondLog".into());
+        config.insert("SECOND.sec_credential_directory_oauth".into(), "/var/lib/condor/second".into());
+        config.insert("SEC_CREDENTIAL_DIRECTORY_OAUTH".into(), "/var/lib/condor/oauth".into());
+        config.insert("other.CREDMON_OAUTH_LOG".into(), "/var/log/condor/OtherLog".into());
+
+        apply_local_name(&mut config, None, Some("second"));
+        assert_eq!(config.get("CREDMON_OAUTH_LOG").unwrap(), "/var/log/condor/SecondLog");
+        assert_eq!(config.get("SEC_CREDENTIAL_DIRECTORY_OAUTH").unwrap(), "/var/lib/condor/second");
+        assert!(config.get("sec_credential_directory_oauth").is_none());
+    }
+
+    #[test]
+    fn test_apply_local_name_precedence() {
+        let mut config: Config = Map::new();
+        config.insert("A".into(), "plain".into());
+        config.insert("CREDMON_OAUTH.A".into(), "subsys".into());
+        config.insert("B".into(), "plain".into());
+        config.insert("CREDMON_OAUTH.B".into(), "subsys".into());
+        config.insert("second.B".into(), "local".into());
+        config.insert("C".into(), "plain".into());
+        config.insert("second.C".into(), "local".into());
+        config.insert("CREDMON_OAUTH.second.C".into(), "subsys_local".into());
+        config.insert("D".into(), "plain".into());
+
+        let mut without_local = config.clone();
+        apply_local_name(&mut without_local, Some(CREDMON_SUBSYSTEM), None);
+        assert_eq!(without_local.get("A").unwrap(), "subsys");
+        assert_eq!(without_local.get("B").unwrap(), "subsys");
+        assert_eq!(without_local.get("C").unwrap(), "plain");
+
+        apply_local_name(&mut config, Some(CREDMON_SUBSYSTEM), Some("second"));
+        assert_eq!(config.get("A").unwrap(), "subsys");
+        assert_eq!(config.get("B").unwrap(), "local");
+        assert_eq!(config.get("C").unwrap(), "subsys_local");
+        assert_eq!(config.get("D").unwrap(), "plain");
+    }
+
+    #[test]
+    fn test_apply_config_options() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, b"CREDMON_OAUTH_TOKEN_MINIMUM = 100\nsecond.CREDMON_OAUTH_TOKEN_REFRESH = 5\n").unwrap();
+
+        let mut config: Config = Map::new();
+        config.insert("CREDMON_OAUTH_TOKEN_MINIMUM".into(), "360".into());
+        config.insert("CREDMON_OAUTH_TOKEN_REFRESH".into(), "60".into());
+
+        let options = ConfigOptions {
+            subsystem: None,
+            local_name: Some("second".into()),
+            override_file: Some(file.path().into()),
+        };
+        apply_config_options(&mut config, &options).unwrap();
+        assert_eq!(config.get("CREDMON_OAUTH_TOKEN_MINIMUM").unwrap(), "100");
+        assert_eq!(config.get("CREDMON_OAUTH_TOKEN_REFRESH").unwrap(), "5");
+    }
 }
@@ -1,25 +1,48 @@
-use oauth2::basic::BasicTokenType;
-use oauth2::{ClientId, ClientSecret, ExtraTokenFields, TokenResponse};
+use oauth2::{ClientId, TokenResponse};
 use openidconnect::IssuerUrl;
 use serde::{Deserialize, Serialize};
 use serde_json;
-use std::collections::{HashMap, HashSet};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::error::Error;
 use std::fs;
 use std::fs::File;
 use std::io::BufReader;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::config::Config;
+use crate::client_auth::ClientAuth;
+use crate::config::{Config, coerce_to_int, get_str};
 use crate::error::CredmonError;
+use crate::jwt::{VerifyConfig, refresh_token_expiry, token_expiry};
+use crate::token_response::IssuerTokenResponse;
 
-#[derive(Serialize, Deserialize)]
+/// Access token lifetime to assume if neither the issuer nor the token says.
+pub const DEFAULT_ACCESS_TOKEN_LIFETIME: Duration = Duration::from_secs(600);
+
+#[derive(Serialize, Deserialize, Default)]
 pub struct RefreshFile {
-    pub refresh_token: String,
+    /// Missing for providers that do not use refresh tokens.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    /// Missing in files stored by `condor_vault_storer`.
+    #[serde(default)]
     pub scopes: String,
+    /// The issuer identity the tokens were requested for. Missing for client
+    /// credentials, and in files stored by `condor_vault_storer`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    /// When the refresh token expires, if the issuer says.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_expires_at: Option<f64>,
+    /// Identity claims from the issuer's ID token, like `preferred_username`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub id_token_claims: BTreeMap<String, Value>,
+    /// Extra access tokens to keep with this refresh token.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub profiles: Vec<AccessProfile>,
 }
 
 impl RefreshFile {
@@ -30,14 +53,69 @@ impl RefreshFile {
         Ok(data)
     }
 
+    /// Write the file, replacing any old one in a single step.
+    ///
+    /// Issuers that rotate refresh tokens invalidate the old one, so a
+    /// half-written file could lose the only working refresh token.
     pub fn write_to_file<P: AsRef<Path>>(self, path: P) -> Result<(), Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("top.tmp");
         let json_string = serde_json::to_string_pretty(&self)?;
-        let mut file = File::create(path)?;
+        let mut file = File::create(&tmp_path)?;
         file.write_all(json_string.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
         Ok(())
     }
 }
 
+/// An extra access token kept for a credential, with its own scopes and audience.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AccessProfile {
+    pub name: String,
+    /// Space separated scopes to request, or the credential's own if empty.
+    #[serde(default)]
+    pub scopes: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audience: Option<String>,
+    /// Name of the access token file, next to the credential's `.use` file.
+    pub file: String,
+}
+
+impl AccessProfile {
+    /// Path of the access token file for a refresh token file.
+    pub fn path_for(&self, refresh_path: &Path) -> PathBuf {
+        refresh_path.with_file_name(&self.file)
+    }
+
+    /// Read the profiles in `<provider>_ACCESS_PROFILES` for a credential.
+    ///
+    /// Each profile has optional `<provider>_ACCESS_PROFILE_<name>_SCOPES`,
+    /// `_AUDIENCE` and `_FILE` settings. Files default to
+    /// `<credential>_<name>.use`, so credentials can't be stored with a
+    /// handle matching a profile name.
+    pub fn from_config(provider_name: &str, credential_name: &str, config: &Config) -> Result<Vec<Self>, Box<dyn Error>> {
+        let mut ret = Vec::new();
+        let names = get_str(config, &format!("{provider_name}_ACCESS_PROFILES"))?.unwrap_or_default();
+        for name in names.split([',', ' ']).filter(|x| !x.is_empty()) {
+            let prefix = format!("{provider_name}_ACCESS_PROFILE_{name}");
+            let file = get_str(config, &format!("{prefix}_FILE"))?.map_or(format!("{credential_name}_{name}.use"), str::to_string);
+            if file.contains('/') || file.starts_with('.') || !file.ends_with(".use") || file == format!("{credential_name}.use") {
+                return Err(Box::new(CredmonError::ConfigError(format!(
+                    "{prefix}_FILE {file:?} is not a valid access token file name"
+                ))));
+            }
+            ret.push(Self {
+                name: name.to_string(),
+                scopes: get_str(config, &format!("{prefix}_SCOPES"))?.unwrap_or_default().to_string(),
+                audience: get_str(config, &format!("{prefix}_AUDIENCE"))?.map(str::to_string),
+                file,
+            });
+        }
+        Ok(ret)
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct AccessFile {
     pub access_token: String,
@@ -63,38 +141,189 @@ impl AccessFile {
     }
 }
 
-pub fn write_tokens_to_file<EF: ExtraTokenFields>(
+/// Seconds since the epoch, as stored in credential files.
+pub fn to_epoch(t: SystemTime) -> f64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64()
+}
+
+pub fn from_epoch(t: f64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs_f64(t.max(0.0))
+}
+
+/// Refresh state persisted next to a credential, so that it survives restarts.
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct StateFile {
+    #[serde(default)]
+    pub last_success: Option<f64>,
+    #[serde(default)]
+    pub last_error: Option<String>,
+    #[serde(default)]
+    pub consecutive_failures: u32,
+    #[serde(default)]
+    pub next_attempt: Option<f64>,
+    #[serde(default)]
+    pub quarantined_at: Option<f64>,
+    /// The refresh token expiry the user was last warned about.
+    #[serde(default)]
+    pub expiry_notified: Option<f64>,
+}
+
+impl StateFile {
+    /// Path of the state file for a refresh token file.
+    pub fn path_for(refresh_path: &Path) -> PathBuf {
+        refresh_path.with_extension("state")
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = File::open(path)?;
+        let reader = BufReader::new(file);
+        let data = serde_json::from_reader(reader)?;
+        Ok(data)
+    }
+
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn std::error::Error>> {
+        let json_string = serde_json::to_string_pretty(self)?;
+        let mut file = File::create(path)?;
+        file.write_all(json_string.as_bytes())?;
+        Ok(())
+    }
+}
+
+fn response_scopes(result: &IssuerTokenResponse) -> Vec<String> {
+    let mut scopes = Vec::new();
+    if let Some(s) = result.scopes() {
+        scopes.extend(s.iter().map(|x| x.as_str().to_string()));
+    }
+    scopes
+}
+
+/// Write the refresh token and access token files for a token response.
+///
+/// Issuers need not return a refresh token on refresh, in which case the
+/// previous one stays in use. ID token claims are also kept from the previous
+/// refresh token file if the response has no ID token.
+pub fn write_tokens_to_file(
     refresh_path: &Path,
-    result: oauth2::StandardTokenResponse<EF, BasicTokenType>,
+    result: IssuerTokenResponse,
+    subject: Option<String>,
+    profiles: Vec<AccessProfile>,
+    default_lifetime: Duration,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let access_path = refresh_path.with_extension("use");
-
-    let parent_path = access_path.parent().unwrap();
+    let parent_path = refresh_path.parent().unwrap();
     if !parent_path.exists() {
         fs::create_dir_all(parent_path)?;
     }
 
     // now write the refresh token
     log::info!("Writing refresh token at {}", refresh_path.to_str().unwrap());
-    let mut scopes = Vec::new();
-    if let Some(s) = result.scopes() {
-        //println!("Scopes: {:?}", s);
-        scopes.extend(s.iter().map(|x| x.as_str().to_string()));
+    let scopes = response_scopes(&result);
+    let old = RefreshFile::from_file(refresh_path).ok();
+    let refresh_expires_in = result.extra_fields().refresh_expires_in;
+    let now = SystemTime::now();
+
+    let (refresh_token, refresh_expires_at) = match (result.refresh_token(), &old) {
+        (Some(new), _) => {
+            if old.as_ref().and_then(|x| x.refresh_token.as_ref()).is_some_and(|x| x != new.secret()) {
+                log::info!("  Refresh token rotated");
+            }
+            (new.secret().clone(), refresh_token_expiry(new.secret(), refresh_expires_in, now))
+        }
+        (
+            None,
+            Some(RefreshFile {
+                refresh_token: Some(old_token),
+                refresh_expires_at,
+                ..
+            }),
+        ) => {
+            log::info!("  No new refresh token, keeping the previous one");
+            let refresh_expires_at = match refresh_expires_in {
+                Some(_) => refresh_token_expiry(old_token, refresh_expires_in, now),
+                None => *refresh_expires_at,
+            };
+            (old_token.clone(), refresh_expires_at)
+        }
+        (None, _) => return Err(Box::new(CredmonError::MissingRefreshToken("issuer did not return a refresh token".into()))),
+    };
+
+    let mut id_token_claims = result.extra_fields().id_token_claims();
+    if id_token_claims.is_empty()
+        && let Some(old) = old
+    {
+        id_token_claims = old.id_token_claims;
     }
 
     RefreshFile {
-        refresh_token: result.refresh_token().unwrap().clone().into_secret(),
+        refresh_token: Some(refresh_token),
         scopes: scopes.join(" "),
+        subject,
+        refresh_expires_at,
+        id_token_claims,
+        profiles,
     }
     .write_to_file(refresh_path)?;
 
+    write_access_token_to_file(refresh_path, &result, scopes, default_lifetime)
+}
+
+/// Write just the access token, next to the refresh token file.
+///
+/// If the response does not list scopes, `default_scopes` are recorded.
+/// If neither the response nor the token says when it expires, it is assumed
+/// to last `default_lifetime`.
+pub fn write_access_token_to_file(
+    refresh_path: &Path,
+    result: &IssuerTokenResponse,
+    default_scopes: Vec<String>,
+    default_lifetime: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    write_access_file(&refresh_path.with_extension("use"), result, default_scopes, default_lifetime)
+}
+
+/// Write the access token for a profile, and keep the refresh token if the
+/// issuer rotated it.
+pub fn write_profile_tokens(
+    refresh_path: &Path,
+    profile: &AccessProfile,
+    result: &IssuerTokenResponse,
+    default_lifetime: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut refresh_file = RefreshFile::from_file(refresh_path)?;
+    let scopes = match profile.scopes.as_str() {
+        "" => refresh_file.scopes.split_whitespace().map(String::from).collect(),
+        x => x.split_whitespace().map(String::from).collect(),
+    };
+
+    if let Some(new) = result.refresh_token()
+        && refresh_file.refresh_token.as_ref() != Some(new.secret())
+    {
+        log::info!("  Refresh token rotated");
+        refresh_file.refresh_token = Some(new.secret().clone());
+        refresh_file.refresh_expires_at = refresh_token_expiry(new.secret(), result.extra_fields().refresh_expires_in, SystemTime::now());
+        refresh_file.write_to_file(refresh_path)?;
+    }
+
+    write_access_file(&profile.path_for(refresh_path), result, scopes, default_lifetime)
+}
+
+fn write_access_file(
+    access_path: &Path,
+    result: &IssuerTokenResponse,
+    default_scopes: Vec<String>,
+    default_lifetime: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let parent_path = access_path.parent().unwrap();
+    if !parent_path.exists() {
+        fs::create_dir_all(parent_path)?;
+    }
+
+    let scopes = match result.scopes() {
+        Some(_) => response_scopes(result),
+        None => default_scopes,
+    };
+
     log::info!("Writing access token at {}", access_path.to_str().unwrap());
-    let exp: u64 = result.expires_in().unwrap_or(Duration::from_secs(600)).as_secs();
-    let exp_at = SystemTime::now()
-        .checked_add(Duration::from_secs(exp))
-        .unwrap()
-        .duration_since(UNIX_EPOCH)?
-        .as_secs_f64();
+    let (exp, exp_at) = token_expiry(result.access_token().secret(), result.expires_in(), default_lifetime, SystemTime::now());
     AccessFile {
         access_token: result.access_token().clone().into_secret(),
         token_type: result.token_type().as_ref().to_string(),
@@ -132,9 +361,13 @@ impl Args {
         if argv.len() < 2 {
             return Err(Box::new(CredmonError::ArgumentError("need to specify scopes and options (provider)".into())));
         }
+        Self::parse(&argv[1])
+    }
 
+    /// Parse the storer argument condor passes, like `options=provider&scopes=a,b&handle=h`.
+    pub fn parse(request: &str) -> Result<Self, Box<dyn Error>> {
         let mut args = HashMap::new();
-        for entry in argv[1].split('&') {
+        for entry in request.split('&') {
             if let Some((key, val)) = entry.split_once('=') {
                 args.insert(key.to_string(), val.to_string());
             }
@@ -154,14 +387,135 @@ impl Args {
 
         let handle = args.get("handle").map(|h| h.to_owned());
 
+        // these become file names in the credential directory
+        for name in [Some(&provider), handle.as_ref()].into_iter().flatten() {
+            if name.is_empty() || name.contains('/') || name.starts_with('.') {
+                return Err(Box::new(CredmonError::ArgumentError(format!("invalid provider or handle {name:?}"))));
+            }
+        }
+
         Ok(Self { provider, scopes, handle })
     }
 }
 
+/// Credmon daemon arguments
+///
+/// Accepts the standard flags condor_master passes to daemons,
+/// plus our own long options.
+#[derive(Default, Debug, PartialEq)]
+pub struct DaemonArgs {
+    pub foreground: bool,
+    pub stderr: bool,
+    pub port: Option<u16>,
+    pub pidfile: Option<PathBuf>,
+    pub local_name: Option<String>,
+    pub config_file: Option<PathBuf>,
+    pub once: bool,
+    pub dry_run: bool,
+    /// Other DaemonCore flags, accepted so condor_master can start us,
+    /// but not used.
+    pub ignored: Vec<String>,
+}
+
+/// DaemonCore flags that take a value.
+const DAEMONCORE_VALUE_FLAGS: [&str; 6] = ["-a", "-c", "-k", "-l", "-r", "-sock"];
+
+pub const DAEMON_USAGE: &str = "usage: condor_credmon_rust [-f] [-b] [-t] [-p <port>] [-pidfile <file>] [-local-name <name>]
+                          [--stderr] [--config <file>] [--once [--dry-run]]
+
+  -f, -b               run in the foreground / background (the credmon never forks)
+  -t, --stderr         log to stderr instead of CREDMON_OAUTH_LOG
+  -p <port>            accepted for condor_master, unused
+  -pidfile <file>      write the process id to this file
+  -local-name <name>   prefer <name>.-prefixed config knobs
+  other -flags         other DaemonCore flags are accepted and ignored
+  --config <file>      read config overrides (KEY = VALUE lines) from this file
+  --once               do a single refresh pass and exit
+  --dry-run            with --once, only report what would be done";
+
+impl DaemonArgs {
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        let argv: Vec<String> = env::args().collect();
+        Self::from_env_impl(argv)
+    }
+
+    fn from_env_impl(argv: Vec<String>) -> Result<Self, Box<dyn Error>> {
+        fn value<'a>(iter: &mut impl Iterator<Item = &'a String>, flag: &str) -> Result<&'a String, CredmonError> {
+            iter.next().ok_or(CredmonError::ArgumentError(format!("{flag} needs a value")))
+        }
+
+        let mut args = Self::default();
+        let mut iter = argv.iter().skip(1);
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "-f" => args.foreground = true,
+                "-b" => args.foreground = false,
+                "-t" | "--stderr" => args.stderr = true,
+                "-p" => {
+                    let port = value(&mut iter, arg)?;
+                    args.port = Some(port.parse().map_err(|_| CredmonError::ArgumentError(format!("bad port {port}")))?);
+                }
+                "-pidfile" => args.pidfile = Some(value(&mut iter, arg)?.into()),
+                "-local-name" => args.local_name = Some(value(&mut iter, arg)?.to_owned()),
+                "--config" => args.config_file = Some(value(&mut iter, arg)?.into()),
+                "--once" => args.once = true,
+                "--dry-run" => args.dry_run = true,
+                "-h" | "--help" => return Err(Box::new(CredmonError::ArgumentError(DAEMON_USAGE.into()))),
+                x if DAEMONCORE_VALUE_FLAGS.contains(&x) => args.ignored.push(format!("{x} {}", value(&mut iter, arg)?)),
+                x if x.starts_with('-') && !x.starts_with("--") => args.ignored.push(x.to_string()),
+                _ => return Err(Box::new(CredmonError::ArgumentError(format!("unknown argument {arg}\n{DAEMON_USAGE}")))),
+            }
+        }
+
+        if args.dry_run && !args.once {
+            return Err(Box::new(CredmonError::ArgumentError("--dry-run requires --once".into())));
+        }
+
+        Ok(args)
+    }
+}
+
+/// How the credmon gets tokens for a provider, from `<provider>_PROVIDER_TYPE`.
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+pub enum ProviderType {
+    /// Exchange for a refresh token on behalf of the user, then refresh it.
+    #[default]
+    TokenExchange,
+    /// Get access tokens directly with the client's own credentials,
+    /// for service accounts. There is no refresh token.
+    ClientCredentials,
+    /// Sign tokens with a local key, see [`crate::local_issuer`].
+    LocalIssuer,
+    /// Get access tokens from Vault with the user's vault token, see [`crate::vault`].
+    Vault,
+}
+
+impl ProviderType {
+    pub fn from_config(provider_name: &str, config: &Config) -> Result<Self, Box<dyn Error>> {
+        let key = format!("{provider_name}_PROVIDER_TYPE");
+        match config.get(&key) {
+            None => Ok(Self::default()),
+            Some(x) => match x.as_str().map(|x| x.to_ascii_uppercase()).as_deref() {
+                Some("TOKEN_EXCHANGE") => Ok(Self::TokenExchange),
+                Some("CLIENT_CREDENTIALS") => Ok(Self::ClientCredentials),
+                Some("LOCAL_ISSUER") => Ok(Self::LocalIssuer),
+                Some("VAULT") => Ok(Self::Vault),
+                _ => Err(Box::new(CredmonError::ConfigError(format!(
+                    "{key} must be TOKEN_EXCHANGE, CLIENT_CREDENTIALS, LOCAL_ISSUER or VAULT"
+                )))),
+            },
+        }
+    }
+}
+
 pub struct ClientInfo {
     pub issuer_url: IssuerUrl,
     pub client_id: ClientId,
-    pub client_secret: ClientSecret,
+    pub client_auth: ClientAuth,
+    /// Checks to make on issued access tokens, if enabled.
+    pub verify_tokens: Option<VerifyConfig>,
+    /// How long access tokens last if the issuer does not say.
+    pub access_token_lifetime: Duration,
 }
 
 impl ClientInfo {
@@ -188,18 +542,19 @@ impl ClientInfo {
         );
         log::info!(target: "refresh", "  client_id = {}", client_id.as_str());
 
-        let client_secret_key = format!("{provider_name}_CLIENT_SECRET_FILE");
-        let client_secret_file = config
-            .get(&client_secret_key)
-            .ok_or(CredmonError::OAuthDirError(format!("missing {client_secret_key} in config")))?
-            .as_str()
-            .ok_or(CredmonError::OAuthDirError(format!("{client_secret_key} is not a string")))?;
-        let client_secret = ClientSecret::new(fs::read_to_string(client_secret_file)?);
+        let client_auth = ClientAuth::from_config(provider_name, config)?;
+        let verify_tokens = VerifyConfig::from_config(provider_name, config)?;
+        let access_token_lifetime = match config.get(&format!("{provider_name}_ACCESS_TOKEN_LIFETIME")) {
+            Some(x) => Duration::from_secs(coerce_to_int(x)?),
+            None => DEFAULT_ACCESS_TOKEN_LIFETIME,
+        };
 
         Ok(Self {
             issuer_url,
             client_id,
-            client_secret,
+            client_auth,
+            verify_tokens,
+            access_token_lifetime,
         })
     }
 }
@@ -276,6 +631,130 @@ mod tests {
         assert_eq!(ret.handle, Some("baz".into()));
     }
 
+    #[test]
+    fn test_args_bad_names() {
+        test_logger();
+        assert!(Args::parse("options=../../etc&scopes=foo").is_err());
+        assert!(Args::parse("options=provider&handle=a/b").is_err());
+        assert!(Args::parse("options=.hidden").is_err());
+        assert!(Args::parse("options=").is_err());
+        assert!(Args::parse("options=provider&handle=baz").is_ok());
+    }
+
+    #[test]
+    fn test_state_file() {
+        test_logger();
+        let tmp = NamedTempFile::new().unwrap();
+        let path = StateFile::path_for(tmp.path());
+        assert_eq!(path.extension().unwrap(), "state");
+
+        let state = StateFile {
+            last_success: Some(10.0),
+            last_error: Some("RequestError: invalid_grant".into()),
+            consecutive_failures: 2,
+            next_attempt: Some(20.0),
+            quarantined_at: None,
+            expiry_notified: Some(30.0),
+        };
+        state.write_to_file(tmp.path()).unwrap();
+        assert_eq!(StateFile::from_file(tmp.path()).unwrap(), state);
+
+        std::fs::write(tmp.path(), "{}").unwrap();
+        assert_eq!(StateFile::from_file(tmp.path()).unwrap(), StateFile::default());
+    }
+
+    #[test]
+    fn test_daemon_args() {
+        test_logger();
+        let fake_args = vec![String::from("exec")];
+        assert_eq!(DaemonArgs::from_env_impl(fake_args).unwrap(), DaemonArgs::default());
+
+        let fake_args = vec![String::from("exec"), String::from("--once"), String::from("--dry-run")];
+        let ret = DaemonArgs::from_env_impl(fake_args).unwrap();
+        assert!(ret.once);
+        assert!(ret.dry_run);
+
+        let fake_args = vec![String::from("exec"), String::from("--dry-run")];
+        let ret = DaemonArgs::from_env_impl(fake_args);
+        assert!(ret.err().unwrap().to_string().contains("requires --once"));
+
+        let fake_args = vec![String::from("exec"), String::from("--bogus")];
+        let ret = DaemonArgs::from_env_impl(fake_args);
+        assert!(ret.err().unwrap().to_string().contains("unknown argument"));
+    }
+
+    #[test]
+    fn test_daemon_args_condor_master() {
+        test_logger();
+        let fake_args: Vec<String> = ["exec", "-f", "-t", "-p", "9618", "-local-name", "second", "-pidfile", "/run/credmon.pid"]
+            .map(String::from)
+            .to_vec();
+        let ret = DaemonArgs::from_env_impl(fake_args).unwrap();
+        assert!(ret.foreground);
+        assert!(ret.stderr);
+        assert_eq!(ret.port, Some(9618));
+        assert_eq!(ret.local_name, Some("second".into()));
+        assert_eq!(ret.pidfile, Some("/run/credmon.pid".into()));
+
+        let fake_args: Vec<String> = ["exec", "-local-name"].map(String::from).to_vec();
+        let ret = DaemonArgs::from_env_impl(fake_args);
+        assert!(ret.err().unwrap().to_string().contains("needs a value"));
+
+        let fake_args: Vec<String> = ["exec", "-p", "foo"].map(String::from).to_vec();
+        let ret = DaemonArgs::from_env_impl(fake_args);
+        assert!(ret.err().unwrap().to_string().contains("bad port"));
+
+        // other DaemonCore flags are ignored, values and all
+        let fake_args: Vec<String> = ["exec", "-f", "-sock", "credmon_123", "-d", "-q", "-k", "/tmp/kill", "-l", "/var/log", "--once"]
+            .map(String::from)
+            .to_vec();
+        let ret = DaemonArgs::from_env_impl(fake_args).unwrap();
+        assert!(ret.foreground);
+        assert!(ret.once);
+        assert_eq!(ret.ignored, ["-sock credmon_123", "-d", "-q", "-k /tmp/kill", "-l /var/log"]);
+
+        let fake_args: Vec<String> = ["exec", "stray"].map(String::from).to_vec();
+        assert!(DaemonArgs::from_env_impl(fake_args).is_err());
+    }
+
+    #[test]
+    fn test_provider_type() {
+        test_logger();
+        let mut config: serde_json::Map<String, Value> = serde_json::Map::new();
+        assert_eq!(ProviderType::from_config("test", &config).unwrap(), ProviderType::TokenExchange);
+
+        config.insert("test_PROVIDER_TYPE".into(), "client_credentials".into());
+        assert_eq!(ProviderType::from_config("test", &config).unwrap(), ProviderType::ClientCredentials);
+
+        config.insert("test_PROVIDER_TYPE".into(), "LOCAL_ISSUER".into());
+        assert_eq!(ProviderType::from_config("test", &config).unwrap(), ProviderType::LocalIssuer);
+
+        config.insert("test_PROVIDER_TYPE".into(), "vault".into());
+        assert_eq!(ProviderType::from_config("test", &config).unwrap(), ProviderType::Vault);
+
+        config.insert("test_PROVIDER_TYPE".into(), "bogus".into());
+        assert!(ProviderType::from_config("test", &config).is_err());
+    }
+
+    #[test]
+    fn test_refresh_file_without_token() {
+        test_logger();
+        let tmp = NamedTempFile::new().unwrap();
+        RefreshFile {
+            scopes: "foo".into(),
+            ..Default::default()
+        }
+        .write_to_file(tmp.path())
+        .unwrap();
+        let contents = fs::read_to_string(tmp.path()).unwrap();
+        assert!(!contents.contains("refresh_token"));
+        assert!(!contents.contains("subject"));
+        let ret = RefreshFile::from_file(tmp.path()).unwrap();
+        assert_eq!(ret.refresh_token, None);
+        assert_eq!(ret.scopes, "foo");
+        assert_eq!(ret.subject, None);
+    }
+
     #[test]
     fn test_client_info() {
         test_logger();
@@ -294,8 +773,46 @@ mod tests {
             Ok(x) => {
                 assert_eq!(x.issuer_url.as_str(), "http://foo");
                 assert_eq!(x.client_id.as_str(), "client");
-                assert_eq!(x.client_secret.secret().as_str(), "secret");
+                assert_eq!(x.client_auth.secret().unwrap(), "secret");
             }
         }
     }
+
+    #[test]
+    fn test_access_profiles() {
+        test_logger();
+        let mut config = Config::new();
+        assert_eq!(AccessProfile::from_config("test", "test_h", &config).unwrap(), vec![]);
+
+        config.insert("test_ACCESS_PROFILES".into(), "read, write".into());
+        config.insert("test_ACCESS_PROFILE_read_SCOPES".into(), "storage.read:/".into());
+        config.insert("test_ACCESS_PROFILE_write_SCOPES".into(), "storage.modify:/out".into());
+        config.insert("test_ACCESS_PROFILE_write_AUDIENCE".into(), "https://storage.example.org".into());
+        let ret = AccessProfile::from_config("test", "test_h", &config).unwrap();
+        assert_eq!(
+            ret,
+            vec![
+                AccessProfile {
+                    name: "read".into(),
+                    scopes: "storage.read:/".into(),
+                    audience: None,
+                    file: "test_h_read.use".into(),
+                },
+                AccessProfile {
+                    name: "write".into(),
+                    scopes: "storage.modify:/out".into(),
+                    audience: Some("https://storage.example.org".into()),
+                    file: "test_h_write.use".into(),
+                },
+            ]
+        );
+        assert_eq!(ret[0].path_for(Path::new("/creds/user/test_h.top")), Path::new("/creds/user/test_h_read.use"));
+
+        config.insert("test_ACCESS_PROFILE_read_FILE".into(), "storage.use".into());
+        assert_eq!(AccessProfile::from_config("test", "test_h", &config).unwrap()[0].file, "storage.use");
+        for bad in ["../evil.use", ".hidden.use", "test_h.use", "test_h_read.top"] {
+            config.insert("test_ACCESS_PROFILE_read_FILE".into(), bad.into());
+            assert!(AccessProfile::from_config("test", "test_h", &config).is_err());
+        }
+    }
 }
@@ -7,10 +7,13 @@ pub enum CredmonError {
     DiscoveryError(String),
     ClientCredenialsError(String),
     MissingRefreshToken(String),
+    RefreshTokenRevoked(String),
     OAuthDirError(String),
     RequestError(String),
     IssuerError(String),
+    IssuerUnavailable(String),
     ConfigError(String),
+    TokenVerificationError(String),
     GenericError(String),
 }
 
@@ -23,10 +26,13 @@ impl fmt::Display for CredmonError {
             CredmonError::DiscoveryError(details) => write!(f, "DiscoveryError: {details}"),
             CredmonError::ClientCredenialsError(details) => write!(f, "ClientCredenialsError: {details}"),
             CredmonError::MissingRefreshToken(details) => write!(f, "MissingRefreshToken: {details}"),
+            CredmonError::RefreshTokenRevoked(details) => write!(f, "RefreshTokenRevoked: {details}"),
             CredmonError::OAuthDirError(details) => write!(f, "OAuthDirError: {details}"),
             CredmonError::RequestError(details) => write!(f, "RequestError: {details}"),
             CredmonError::IssuerError(details) => write!(f, "IssuerError: {details}"),
+            CredmonError::IssuerUnavailable(details) => write!(f, "IssuerUnavailable: {details}"),
             CredmonError::ConfigError(details) => write!(f, "ConfigError: {details}"),
+            CredmonError::TokenVerificationError(details) => write!(f, "TokenVerificationError: {details}"),
             CredmonError::GenericError(details) => write!(f, "GenericError: {details}"),
         }
     }
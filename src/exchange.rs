@@ -1,61 +1,239 @@
-use oauth2::ExtraTokenFields;
-use oauth2::basic::BasicTokenType;
-use openidconnect::OAuth2TokenResponse;
-use openidconnect::core::CoreProviderMetadata;
-use openidconnect::reqwest;
+use oauth2::basic::{BasicErrorResponse, BasicRevocationErrorResponse, BasicTokenIntrospectionResponse};
+use oauth2::{AuthType, ClientSecret, EndpointNotSet, EndpointSet, StandardRevocableToken, TokenUrl};
+use openidconnect::core::{
+    CoreAuthDisplay, CoreClaimName, CoreClaimType, CoreClientAuthMethod, CoreGrantType, CoreJsonWebKey, CoreJweContentEncryptionAlgorithm,
+    CoreJweKeyManagementAlgorithm, CoreResponseMode, CoreResponseType, CoreSubjectIdentifierType,
+};
+use openidconnect::{AdditionalProviderMetadata, OAuth2TokenResponse, Scope};
 use serde::{Deserialize, Serialize};
+use zeroize::Zeroize;
 
-use crate::config::config as condor_config;
+use crate::config::{Config, get_str};
 use crate::data::{Args, ClientInfo};
 use crate::error::CredmonError;
+use crate::http_client::{HttpClient, token_request_error};
+use crate::jwt::check_access_token;
+use crate::mapfile::MapFile;
+use crate::token_response::IssuerTokenResponse;
 
-#[derive(Deserialize, Debug, Serialize)]
-pub struct CustomTokenExtraFields {
-    issued_token_type: String,
+/// An OAuth client for an issuer's token endpoint.
+pub type TokenClient = oauth2::Client<
+    BasicErrorResponse,
+    IssuerTokenResponse,
+    BasicTokenIntrospectionResponse,
+    StandardRevocableToken,
+    BasicRevocationErrorResponse,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointSet,
+>;
+
+/// Endpoints to use when authenticating with a TLS client certificate (RFC 8705).
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct MtlsEndpointAliases {
+    #[serde(default)]
+    pub token_endpoint: Option<TokenUrl>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ExtraProviderMetadata {
+    #[serde(default)]
+    pub mtls_endpoint_aliases: Option<MtlsEndpointAliases>,
+}
+impl AdditionalProviderMetadata for ExtraProviderMetadata {}
+
+pub type ProviderMetadata = openidconnect::ProviderMetadata<
+    ExtraProviderMetadata,
+    CoreAuthDisplay,
+    CoreClientAuthMethod,
+    CoreClaimName,
+    CoreClaimType,
+    CoreGrantType,
+    CoreJweContentEncryptionAlgorithm,
+    CoreJweKeyManagementAlgorithm,
+    CoreJsonWebKey,
+    CoreResponseMode,
+    CoreResponseType,
+    CoreSubjectIdentifierType,
+>;
+
+/// Use OpenID Connect Discovery to fetch the provider metadata.
+///
+/// Clients with a TLS certificate use the issuer's mTLS token endpoint, if it has one.
+pub fn discover_provider(info: &ClientInfo, http_client: &HttpClient) -> Result<ProviderMetadata, CredmonError> {
+    let mut provider_metadata = ProviderMetadata::discover(&info.issuer_url, http_client).map_err(|x| CredmonError::DiscoveryError(x.to_string()))?;
+
+    let alias = provider_metadata
+        .additional_metadata()
+        .mtls_endpoint_aliases
+        .as_ref()
+        .and_then(|x| x.token_endpoint.clone());
+    if let Some(alias) = alias
+        && info.client_auth.identity().is_some()
+    {
+        log::info!("  using mTLS token endpoint {}", alias.as_str());
+        provider_metadata = provider_metadata.set_token_endpoint(Some(alias));
+    }
+    Ok(provider_metadata)
+}
+
+/// Get the token endpoint from the provider metadata.
+pub fn token_endpoint(provider_metadata: &ProviderMetadata) -> Result<String, CredmonError> {
+    match provider_metadata.token_endpoint() {
+        Some(x) => Ok(x.to_string()),
+        None => Err(CredmonError::DiscoveryError("token url not discovered".into())),
+    }
+}
+
+/// Make a client for the issuer's token endpoint, authenticating as configured.
+pub fn token_client(info: &ClientInfo, provider_metadata: &ProviderMetadata) -> Result<TokenClient, CredmonError> {
+    let token_url = TokenUrl::new(token_endpoint(provider_metadata)?).map_err(|e| CredmonError::DiscoveryError(format!("bad token url: {e}")))?;
+    let mut client = oauth2::Client::new(info.client_id.clone())
+        .set_token_uri(token_url)
+        .set_auth_type(info.client_auth.auth_type(provider_metadata.token_endpoint_auth_methods_supported()));
+    if let Some(secret) = info.client_auth.secret() {
+        client = client.set_client_secret(ClientSecret::new(secret.to_string()));
+    }
+    Ok(client)
+}
+
+/// Get an access token with the client's own credentials,
+/// using the `client_credentials` grant.
+pub fn do_client_credentials(info: ClientInfo, scopes: &str) -> Result<IssuerTokenResponse, Box<dyn std::error::Error>> {
+    log::info!("Getting client credentials token");
+    let http_client = HttpClient::for_client(&info)?;
+    let provider_metadata = discover_provider(&info, &http_client)?;
+    let auth_params = info.client_auth.extra_params(info.client_id.as_str(), &token_endpoint(&provider_metadata)?)?;
+    let jwks_uri = provider_metadata.jwks_uri().to_string();
+
+    let client = token_client(&info, &provider_metadata)?;
+    let mut request = client.exchange_client_credentials();
+    for scope in scopes.split_whitespace() {
+        request = request.add_scope(Scope::new(scope.to_string()));
+    }
+    for (key, value) in auth_params {
+        request = request.add_extra_param(key, value);
+    }
+
+    let token_response = request.request(&http_client).map_err(token_request_error)?;
+    // the token is for the client itself, so there is no user to check
+    check_access_token(
+        &info,
+        &jwks_uri,
+        &http_client,
+        token_response.access_token().secret(),
+        token_response.expires_in(),
+        None,
+    )?;
+    Ok(token_response)
+}
+
+const TOKEN_EXCHANGE_GRANT: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+const REFRESH_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:refresh_token";
+
+/// Per-provider token exchange settings.
+///
+/// The user is always identified with Keycloak's `requested_subject`. RFC
+/// 8693's `subject_token` must be a token for the user, which the credmon
+/// doesn't have.
+#[derive(Debug)]
+pub struct TokenExchangeConfig {
+    /// Template for the issuer's subject, with `{user}` replaced by the unix username.
+    pub subject_template: String,
+    pub requested_token_type: String,
+}
+
+impl TokenExchangeConfig {
+    pub fn new(provider_name: &str, config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let prefix = format!("{provider_name}_TOKEN_EXCHANGE");
+
+        let subject_template = get_str(config, &format!("{prefix}_SUBJECT"))?.unwrap_or("{user}").to_string();
+
+        let param_key = format!("{prefix}_SUBJECT_PARAM");
+        if get_str(config, &param_key)?.is_some_and(|x| x != "requested_subject") {
+            return Err(Box::new(CredmonError::ConfigError(format!(
+                "{param_key} must be requested_subject, the only supported way to send the subject"
+            ))));
+        }
+
+        let requested_token_type = get_str(config, &format!("{prefix}_REQUESTED_TOKEN_TYPE"))?
+            .unwrap_or(REFRESH_TOKEN_TYPE)
+            .to_string();
+
+        Ok(Self {
+            subject_template,
+            requested_token_type,
+        })
+    }
+
+    /// Get the issuer's subject for a unix user.
+    pub fn subject_for(&self, username: &str) -> String {
+        self.subject_template.replace("{user}", username)
+    }
+
+    /// Form parameters for the token exchange request.
+    pub fn params(&self, audience: &str, subject: &str, scopes: &str) -> Vec<(&'static str, String)> {
+        vec![
+            ("grant_type", TOKEN_EXCHANGE_GRANT.to_string()),
+            ("audience", audience.to_string()),
+            ("requested_token_type", self.requested_token_type.clone()),
+            ("requested_subject", subject.to_string()),
+            ("scope", scopes.to_string()),
+        ]
+    }
+}
+
+/// Get the issuer identity to request tokens for a unix user.
+///
+/// A configured user mapfile is checked first, then the provider's
+/// token exchange subject template.
+pub fn map_subject(provider: &str, username: &str, config: &Config) -> Result<String, Box<dyn std::error::Error>> {
+    if let Some(subject) = MapFile::for_provider(provider, config)?.and_then(|x| x.map(provider, username)) {
+        return Ok(subject);
+    }
+    Ok(TokenExchangeConfig::new(provider, config)?.subject_for(username))
 }
-impl ExtraTokenFields for CustomTokenExtraFields {}
 
-pub fn do_token_exchange(
-    args: &Args,
-    username: &str,
-) -> Result<oauth2::StandardTokenResponse<CustomTokenExtraFields, BasicTokenType>, Box<dyn std::error::Error>> {
-    let config = condor_config();
+/// Exchange for tokens on behalf of `subject`, the user's issuer identity.
+///
+/// The client info is passed in, so callers can read client secrets
+/// with different privileges than the rest of the exchange.
+pub fn do_token_exchange(args: &Args, info: ClientInfo, subject: &str, config: &Config) -> Result<IssuerTokenResponse, Box<dyn std::error::Error>> {
     log::info!("Getting tokens");
     log::info!("  provider = {}", args.provider);
+    log::info!("  subject = {subject}");
 
-    let info = ClientInfo::new(args.provider.as_str(), &config)?;
+    let exchange_config = TokenExchangeConfig::new(args.provider.as_str(), config)?;
 
-    let http_client = reqwest::blocking::ClientBuilder::new()
-        // Following redirects opens the client up to SSRF vulnerabilities.
-        .redirect(reqwest::redirect::Policy::none())
-        .build()
-        .expect("Client should build");
+    let http_client = HttpClient::for_client(&info)?;
 
-    // Use OpenID Connect Discovery to fetch the provider metadata.
-    let provider_metadata = match CoreProviderMetadata::discover(&info.issuer_url, &http_client) {
-        Ok(x) => Ok(x),
-        Err(x) => Err(CredmonError::DiscoveryError(x.to_string())),
-    }?;
+    let provider_metadata = discover_provider(&info, &http_client)?;
 
-    let token_url = match provider_metadata.token_endpoint() {
-        Some(x) => x.to_string(),
-        None => return Result::Err(Box::new(CredmonError::DiscoveryError("token url not discovered".into()))),
-    };
+    let token_url = token_endpoint(&provider_metadata)?;
 
     // Do token exchange
-    let params = [
-        ("grant_type", "urn:ietf:params:oauth:grant-type:token-exchange"),
-        ("audience", info.client_id.as_str()),
-        ("requested_token_type", "urn:ietf:params:oauth:token-type:refresh_token"),
-        ("requested_subject", username),
-        ("scope", &args.scopes),
-    ];
-
-    let result = http_client
-        .post(token_url.as_str())
-        .basic_auth(info.client_id.as_str(), Some(info.client_secret.secret()))
-        .form(&params)
-        .send()?;
+    let mut params = exchange_config.params(info.client_id.as_str(), subject, &args.scopes);
+
+    log::info!("  POST {token_url}");
+    let mut request = http_client.inner().post(token_url.as_str());
+    match info.client_auth.auth_type(provider_metadata.token_endpoint_auth_methods_supported()) {
+        AuthType::BasicAuth => {
+            request = request.basic_auth(info.client_id.as_str(), info.client_auth.secret());
+        }
+        _ => {
+            params.push(("client_id", info.client_id.to_string()));
+            if let Some(secret) = info.client_auth.secret() {
+                params.push(("client_secret", secret.to_string()));
+            }
+            params.extend(info.client_auth.extra_params(info.client_id.as_str(), &token_url)?);
+        }
+    }
+    let result = request.form(&params).send();
+    params.iter_mut().for_each(|(_, x)| x.zeroize());
+    let result = result?;
+    log::info!("  response status: {}", result.status());
 
     // Verify response
     match result.status().as_u16() {
@@ -64,14 +242,323 @@ pub fn do_token_exchange(
             Err(Box::new(CredmonError::RequestError(err_str)))
         }
         _ => {
-            let body: oauth2::StandardTokenResponse<CustomTokenExtraFields, BasicTokenType> = result.json()?;
+            let body: IssuerTokenResponse = result.json()?;
 
-            match body.refresh_token() {
-                Some(_) => Ok(body),
-                None => Err(Box::new(CredmonError::MissingRefreshToken(
+            // required by RFC 8693
+            if body.extra_fields().issued_token_type.is_none() {
+                return Err(Box::new(CredmonError::RequestError("token exchange did not return issued_token_type".into())));
+            }
+            if body.refresh_token().is_none() {
+                return Err(Box::new(CredmonError::MissingRefreshToken(
                     "token exchange did not return a refresh token".into(),
-                ))),
+                )));
             }
+            check_access_token(
+                &info,
+                &provider_metadata.jwks_uri().to_string(),
+                &http_client,
+                body.access_token().secret(),
+                body.expires_in(),
+                Some(subject),
+            )?;
+            Ok(body)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{DEFAULT_ACCESS_TOKEN_LIFETIME, RefreshFile, write_tokens_to_file};
+    use crate::jwt::tests::sign_token;
+    use oauth2::ClientId;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use zeroize::Zeroizing;
+
+    use crate::client_auth::tests::{TEST_CERT, TEST_EC_KEY};
+    use crate::client_auth::{ClientAuth, ClientKey};
+    use openidconnect::IssuerUrl;
+
+    use crate::logging::test_logger;
+    use crate::test_server::TestServer;
+
+    fn client_info(server: &TestServer) -> ClientInfo {
+        ClientInfo {
+            issuer_url: IssuerUrl::new(server.url.clone()).unwrap(),
+            client_id: ClientId::new("robot".into()),
+            client_auth: ClientAuth::Secret {
+                secret: Zeroizing::new("secret".into()),
+                method: None,
+            },
+            verify_tokens: None,
+            access_token_lifetime: DEFAULT_ACCESS_TOKEN_LIFETIME,
+        }
+    }
+
+    #[test]
+    fn test_client_credentials() {
+        test_logger();
+        let server = TestServer::issuer(200, r#"{"access_token":"at","token_type":"bearer","expires_in":300}"#);
+
+        let ret = do_client_credentials(client_info(&server), "read write").unwrap();
+        assert_eq!(ret.access_token().secret(), "at");
+        assert!(ret.refresh_token().is_none());
+
+        let requests = server.requests_to("/token");
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "POST");
+        assert!(requests[0].body.contains("grant_type=client_credentials"));
+        assert!(requests[0].body.contains("scope=read+write"));
+        assert!(requests[0].headers.contains_key("authorization"));
+    }
+
+    #[test]
+    fn test_client_credentials_private_key_jwt() {
+        test_logger();
+        let server = TestServer::issuer(200, r#"{"access_token":"at","token_type":"bearer","expires_in":300}"#);
+        let mut info = client_info(&server);
+        info.client_auth = ClientAuth::PrivateKeyJwt(ClientKey::from_pem(TEST_EC_KEY.as_bytes(), None, None).unwrap());
+
+        do_client_credentials(info, "read").unwrap();
+
+        let requests = server.requests_to("/token");
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].body.contains("client_id=robot"));
+        assert!(
+            requests[0]
+                .body
+                .contains("client_assertion_type=urn%3Aietf%3Aparams%3Aoauth%3Aclient-assertion-type%3Ajwt-bearer")
+        );
+        assert!(requests[0].body.contains("client_assertion=ey"));
+        assert!(!requests[0].headers.contains_key("authorization"));
+    }
+
+    #[test]
+    fn test_client_credentials_mtls() {
+        test_logger();
+        let server = TestServer::issuer(200, r#"{"access_token":"at","token_type":"bearer","expires_in":300}"#);
+        server.add_metadata(serde_json::json!({
+            "mtls_endpoint_aliases": {"token_endpoint": format!("{}/mtls/token", server.url)},
+        }));
+        server.route("/mtls/token", 200, r#"{"access_token":"at2","token_type":"bearer","expires_in":300}"#);
+
+        // secrets ignore the alias
+        let ret = do_client_credentials(client_info(&server), "read").unwrap();
+        assert_eq!(ret.access_token().secret(), "at");
+
+        let mut info = client_info(&server);
+        let pem = format!("{TEST_CERT}{TEST_EC_KEY}");
+        info.client_auth = ClientAuth::Tls(reqwest::Identity::from_pem(pem.as_bytes()).unwrap());
+        let ret = do_client_credentials(info, "read").unwrap();
+        assert_eq!(ret.access_token().secret(), "at2");
+
+        let requests = server.requests_to("/mtls/token");
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].body.contains("client_id=robot"));
+        assert!(!requests[0].body.contains("client_assertion"));
+        assert!(!requests[0].headers.contains_key("authorization"));
+    }
+
+    #[test]
+    fn test_client_credentials_error() {
+        test_logger();
+        let server = TestServer::issuer(401, r#"{"error":"invalid_client"}"#);
+        let ret = do_client_credentials(client_info(&server), "read");
+        assert!(ret.err().unwrap().to_string().contains("RequestError"));
+    }
+
+    #[test]
+    fn test_token_exchange_config_default() {
+        test_logger();
+        let config = Config::new();
+        let ret = TokenExchangeConfig::new("test", &config).unwrap();
+        assert_eq!(ret.subject_for("alice"), "alice");
+
+        let params = ret.params("client", "alice", "read");
+        assert!(params.contains(&("requested_subject", "alice".into())));
+        assert!(params.contains(&("requested_token_type", REFRESH_TOKEN_TYPE.into())));
+        assert!(params.contains(&("audience", "client".into())));
+        assert!(!params.iter().any(|(k, _)| *k == "subject_token"));
+    }
+
+    #[test]
+    fn test_token_exchange_config_custom() {
+        test_logger();
+        let mut config = Config::new();
+        config.insert("test_TOKEN_EXCHANGE_SUBJECT".into(), "{user}@example.org".into());
+        config.insert(
+            "test_TOKEN_EXCHANGE_REQUESTED_TOKEN_TYPE".into(),
+            "urn:ietf:params:oauth:token-type:access_token".into(),
+        );
+        let ret = TokenExchangeConfig::new("test", &config).unwrap();
+        assert_eq!(ret.subject_for("alice"), "alice@example.org");
+
+        let params = ret.params("client", "alice@example.org", "read");
+        assert!(params.contains(&("requested_subject", "alice@example.org".into())));
+        assert!(params.contains(&("requested_token_type", "urn:ietf:params:oauth:token-type:access_token".into())));
+
+        // a username is not a subject_token, so that isn't offered
+        config.insert("test_TOKEN_EXCHANGE_SUBJECT_PARAM".into(), "requested_subject".into());
+        assert!(TokenExchangeConfig::new("test", &config).is_ok());
+        config.insert("test_TOKEN_EXCHANGE_SUBJECT_PARAM".into(), "subject_token".into());
+        assert!(TokenExchangeConfig::new("test", &config).is_err());
+    }
+
+    #[test]
+    fn test_token_exchange() {
+        test_logger();
+        let server = TestServer::issuer(
+            200,
+            r#"{"access_token":"at","refresh_token":"rt","token_type":"bearer","expires_in":300,"issued_token_type":"urn:ietf:params:oauth:token-type:refresh_token"}"#,
+        );
+        let mut secret = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut secret, b"secret").unwrap();
+
+        let mut config = Config::new();
+        config.insert("test_ISSUER".into(), server.url.clone().into());
+        config.insert("test_CLIENT_ID".into(), "client".into());
+        config.insert("test_CLIENT_SECRET_FILE".into(), secret.path().to_str().into());
+        config.insert("test_TOKEN_EXCHANGE_SUBJECT".into(), "{user}@example.org".into());
+
+        let args = Args {
+            provider: "test".into(),
+            scopes: "read".into(),
+            handle: None,
+        };
+        let subject = map_subject("test", "alice", &config).unwrap();
+        let ret = do_token_exchange(&args, ClientInfo::new("test", &config).unwrap(), &subject, &config).unwrap();
+        assert_eq!(ret.refresh_token().unwrap().secret(), "rt");
+
+        let requests = server.requests_to("/token");
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].body.contains("requested_subject=alice%40example.org"));
+        assert!(
+            requests[0]
+                .body
+                .contains("grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Atoken-exchange")
+        );
+    }
+
+    #[test]
+    fn test_token_exchange_missing_issued_token_type() {
+        test_logger();
+        let server = TestServer::issuer(200, r#"{"access_token":"at","refresh_token":"rt","token_type":"bearer"}"#);
+        let args = Args {
+            provider: "test".into(),
+            scopes: "read".into(),
+            handle: None,
+        };
+        let ret = do_token_exchange(&args, client_info(&server), "alice", &Config::new());
+        assert!(ret.unwrap_err().to_string().contains("issued_token_type"));
+    }
+
+    #[test]
+    fn test_token_exchange_refresh_expiry() {
+        test_logger();
+        let id_token = sign_token(&serde_json::json!({"sub": "1234", "preferred_username": "alice"}));
+        let body = serde_json::json!({
+            "access_token": "at",
+            "refresh_token": "rt",
+            "token_type": "bearer",
+            "expires_in": 300,
+            "refresh_expires_in": 86400,
+            "id_token": id_token,
+            "issued_token_type": "urn:ietf:params:oauth:token-type:refresh_token",
+        });
+        let server = TestServer::issuer(200, &body.to_string());
+        let args = Args {
+            provider: "test".into(),
+            scopes: "read".into(),
+            handle: None,
+        };
+        let ret = do_token_exchange(&args, client_info(&server), "alice", &Config::new()).unwrap();
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("test.top");
+        write_tokens_to_file(&path, ret, None, Vec::new(), DEFAULT_ACCESS_TOKEN_LIFETIME).unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+        let refresh_file = RefreshFile::from_file(&path).unwrap();
+        assert!((refresh_file.refresh_expires_at.unwrap() - now - 86400.0).abs() < 5.0);
+        assert_eq!(refresh_file.id_token_claims["preferred_username"], "alice");
+    }
+
+    #[test]
+    fn test_map_subject() {
+        test_logger();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, b"test /(.*)_robot/ \\1-svc\n").unwrap();
+
+        let mut config = Config::new();
+        config.insert("test_TOKEN_EXCHANGE_SUBJECT".into(), "{user}@example.org".into());
+        assert_eq!(map_subject("test", "build_robot", &config).unwrap(), "build_robot@example.org");
+
+        config.insert("test_USER_MAPFILE".into(), file.path().to_str().into());
+        assert_eq!(map_subject("test", "build_robot", &config).unwrap(), "build-svc");
+        assert_eq!(map_subject("test", "alice", &config).unwrap(), "alice@example.org");
+    }
+
+    #[test]
+    fn test_token_exchange_private_key_jwt() {
+        test_logger();
+        let server = TestServer::issuer(
+            200,
+            r#"{"access_token":"at","refresh_token":"rt","token_type":"bearer","issued_token_type":"x"}"#,
+        );
+        let mut key = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut key, TEST_EC_KEY.as_bytes()).unwrap();
+
+        let mut config = Config::new();
+        config.insert("test_ISSUER".into(), server.url.clone().into());
+        config.insert("test_CLIENT_ID".into(), "client".into());
+        config.insert("test_CLIENT_KEY_FILE".into(), key.path().to_str().into());
+
+        let args = Args {
+            provider: "test".into(),
+            scopes: "read".into(),
+            handle: None,
+        };
+        do_token_exchange(&args, ClientInfo::new("test", &config).unwrap(), "alice", &config).unwrap();
+
+        let requests = server.requests_to("/token");
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].body.contains("client_id=client"));
+        assert!(requests[0].body.contains("client_assertion=ey"));
+        assert!(!requests[0].headers.contains_key("authorization"));
+    }
+
+    #[test]
+    fn test_token_exchange_client_secret_post() {
+        test_logger();
+        let server = TestServer::issuer(
+            200,
+            r#"{"access_token":"at","refresh_token":"rt","token_type":"bearer","issued_token_type":"x"}"#,
+        );
+        server.add_metadata(serde_json::json!({"token_endpoint_auth_methods_supported": ["client_secret_post"]}));
+        let mut secret = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut secret, b"secret").unwrap();
+
+        let mut config = Config::new();
+        config.insert("test_ISSUER".into(), server.url.clone().into());
+        config.insert("test_CLIENT_ID".into(), "client".into());
+        config.insert("test_CLIENT_SECRET_FILE".into(), secret.path().to_str().into());
+
+        let args = Args {
+            provider: "test".into(),
+            scopes: "read".into(),
+            handle: None,
+        };
+        do_token_exchange(&args, ClientInfo::new("test", &config).unwrap(), "alice", &config).unwrap();
+
+        // configured method wins over discovery
+        config.insert("test_TOKEN_ENDPOINT_AUTH_METHOD".into(), "client_secret_basic".into());
+        do_token_exchange(&args, ClientInfo::new("test", &config).unwrap(), "alice", &config).unwrap();
+
+        let requests = server.requests_to("/token");
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].body.contains("client_id=client"));
+        assert!(requests[0].body.contains("client_secret=secret"));
+        assert!(!requests[0].headers.contains_key("authorization"));
+        assert!(!requests[1].body.contains("client_secret"));
+        assert!(requests[1].headers.contains_key("authorization"));
+    }
+}
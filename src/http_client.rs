@@ -0,0 +1,156 @@
+use oauth2::{ErrorResponse, HttpClientError, HttpRequest, HttpResponse, RequestTokenError, SyncHttpClient};
+use reqwest::Identity;
+use serde_json::Value;
+
+use crate::data::ClientInfo;
+use crate::error::CredmonError;
+
+/// Fields of requests and responses that must never be logged.
+const SECRET_FIELDS: [&str; 7] = [
+    "access_token",
+    "refresh_token",
+    "id_token",
+    "client_secret",
+    "client_assertion",
+    "subject_token",
+    "actor_token",
+];
+const REDACTED: &str = "<redacted>";
+
+/// Redact secrets from a form-encoded request body.
+pub fn redact_form(body: &str) -> String {
+    body.split('&')
+        .map(|entry| match entry.split_once('=') {
+            Some((key, _)) if SECRET_FIELDS.contains(&key) => format!("{key}={REDACTED}"),
+            _ => entry.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn redact_value(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, val) in map.iter_mut() {
+                if SECRET_FIELDS.contains(&key.as_str()) {
+                    *val = Value::String(REDACTED.into());
+                } else {
+                    redact_value(val);
+                }
+            }
+        }
+        Value::Array(list) => list.iter_mut().for_each(redact_value),
+        _ => {}
+    }
+}
+
+/// Redact secrets from a JSON response body.
+///
+/// Bodies that are not JSON are not logged at all.
+pub fn redact_json(body: &[u8]) -> String {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(mut value) => {
+            redact_value(&mut value);
+            value.to_string()
+        }
+        Err(_) => format!("<{} bytes of non-json>", body.len()),
+    }
+}
+
+/// Convert an oauth2 token request error.
+///
+/// If the issuer answered with an error, the problem is with the request,
+/// otherwise the issuer is having trouble.
+pub fn token_request_error<RE, T>(e: RequestTokenError<RE, T>) -> CredmonError
+where
+    RE: std::error::Error + 'static,
+    T: ErrorResponse + 'static,
+{
+    match e {
+        RequestTokenError::ServerResponse(x) => CredmonError::RequestError(x.to_string()),
+        RequestTokenError::Request(x) => CredmonError::IssuerUnavailable(x.to_string()),
+        RequestTokenError::Parse(x, _) => CredmonError::IssuerUnavailable(format!("cannot parse response: {x}")),
+        RequestTokenError::Other(x) => CredmonError::IssuerUnavailable(x),
+    }
+}
+
+/// A blocking HTTP client that logs requests and responses, without secrets.
+pub struct HttpClient {
+    inner: reqwest::blocking::Client,
+}
+
+impl HttpClient {
+    pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
+        Self::with_identity(None)
+    }
+
+    /// A client presenting the provider's TLS client certificate, if it has one.
+    pub fn for_client(info: &ClientInfo) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::with_identity(info.client_auth.identity())
+    }
+
+    fn with_identity(identity: Option<&Identity>) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut builder = reqwest::blocking::ClientBuilder::new()
+            // Following redirects opens the client up to SSRF vulnerabilities.
+            .redirect(reqwest::redirect::Policy::none());
+        if let Some(identity) = identity {
+            builder = builder.identity(identity.clone());
+        }
+        Ok(Self { inner: builder.build()? })
+    }
+
+    /// The underlying reqwest client, for requests not made through oauth2.
+    pub fn inner(&self) -> &reqwest::blocking::Client {
+        &self.inner
+    }
+}
+
+impl SyncHttpClient for HttpClient {
+    type Error = HttpClientError<reqwest::Error>;
+
+    fn call(&self, request: HttpRequest) -> Result<HttpResponse, Self::Error> {
+        log::info!("  {} {}", request.method(), request.uri());
+        if !request.body().is_empty() {
+            log::debug!("  request body: {}", redact_form(&String::from_utf8_lossy(request.body())));
+        }
+
+        let response = self.inner.call(request);
+        match &response {
+            Ok(r) => {
+                log::info!("  response status: {}", r.status());
+                log::debug!("  response body: {}", redact_json(r.body()));
+            }
+            Err(e) => log::info!("  request failed: {e}"),
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::test_logger;
+
+    #[test]
+    fn test_redact_form() {
+        test_logger();
+        let body = "grant_type=refresh_token&refresh_token=secret1&client_secret=secret2&scope=foo";
+        let ret = redact_form(body);
+        assert!(!ret.contains("secret1"));
+        assert!(!ret.contains("secret2"));
+        assert!(ret.contains("grant_type=refresh_token"));
+        assert!(ret.contains("scope=foo"));
+    }
+
+    #[test]
+    fn test_redact_json() {
+        test_logger();
+        let body = br#"{"access_token":"secret1","refresh_token":"secret2","expires_in":300,"nested":{"id_token":"secret3"}}"#;
+        let ret = redact_json(body);
+        assert!(!ret.contains("secret"));
+        assert!(ret.contains("expires_in"));
+
+        let ret = redact_json(b"<html>access_token</html>");
+        assert!(!ret.contains("access_token"));
+    }
+}
@@ -0,0 +1,285 @@
+//! Checking access tokens the issuer hands out, before they are stored.
+
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{AlgorithmFamily, DecodingKey, Validation};
+use serde_json::{Map, Value};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::{Config, coerce_to_bool};
+use crate::data::ClientInfo;
+use crate::error::CredmonError;
+use crate::http_client::HttpClient;
+
+/// How far `exp` may be from what `expires_in` says, in seconds.
+const EXP_LEEWAY: u64 = 120;
+
+/// Which checks to make on access tokens, from the provider config.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyConfig {
+    /// The claim that must match the user's issuer identity.
+    pub subject_claim: String,
+}
+
+impl VerifyConfig {
+    /// Read `<provider>_VERIFY_ACCESS_TOKENS` and `<provider>_SUBJECT_CLAIM`.
+    ///
+    /// Returns `None` if verification is not enabled.
+    pub fn from_config(provider_name: &str, config: &Config) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let enabled = match config.get(&format!("{provider_name}_VERIFY_ACCESS_TOKENS")) {
+            Some(x) => coerce_to_bool(x)?,
+            None => false,
+        };
+        if !enabled {
+            return Ok(None);
+        }
+
+        let claim_key = format!("{provider_name}_SUBJECT_CLAIM");
+        let subject_claim = match config.get(&claim_key) {
+            Some(x) => x.as_str().ok_or(CredmonError::ConfigError(format!("{claim_key} is not a string")))?.to_string(),
+            None => "sub".into(),
+        };
+        Ok(Some(Self { subject_claim }))
+    }
+}
+
+/// Fetch the issuer's signing keys.
+pub fn fetch_jwks(jwks_uri: &str, http_client: &HttpClient) -> Result<JwkSet, CredmonError> {
+    log::info!("  GET {jwks_uri}");
+    http_client
+        .inner()
+        .get(jwks_uri)
+        .send()
+        .and_then(|x| x.error_for_status())
+        .and_then(|x| x.json())
+        .map_err(|e| CredmonError::IssuerUnavailable(format!("cannot get jwks: {e}")))
+}
+
+fn verification_error(details: String) -> CredmonError {
+    CredmonError::TokenVerificationError(details)
+}
+
+/// Check that `access_token` is a JWT signed by one of `jwks`, issued by
+/// `issuer` for `subject`, and expiring when `expires_in` says it does.
+///
+/// `subject` is not checked if it is unknown.
+pub fn verify_access_token(
+    access_token: &str,
+    jwks: &JwkSet,
+    issuer: &str,
+    expires_in: Option<Duration>,
+    subject: Option<&str>,
+    verify_config: &VerifyConfig,
+) -> Result<Map<String, Value>, CredmonError> {
+    let header = jsonwebtoken::decode_header(access_token).map_err(|e| verification_error(format!("access token is not a JWT: {e}")))?;
+    if header.alg.family() == AlgorithmFamily::Hmac {
+        return Err(verification_error(format!("access token uses symmetric algorithm {:?}", header.alg)));
+    }
+
+    let jwk = match &header.kid {
+        Some(kid) => jwks.find(kid),
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    }
+    .ok_or(verification_error(format!("no issuer key for kid {:?}", header.kid)))?;
+    let key = DecodingKey::from_jwk(jwk).map_err(|e| verification_error(format!("bad issuer key: {e}")))?;
+
+    // issuers are not consistent about trailing slashes
+    let issuers = [issuer.trim_end_matches('/').to_string(), format!("{}/", issuer.trim_end_matches('/'))];
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&issuers);
+    validation.set_required_spec_claims(&["exp", "iss"]);
+    validation.validate_aud = false;
+    let claims = jsonwebtoken::decode::<Map<String, Value>>(access_token, &key, &validation)
+        .map_err(|e| verification_error(format!("invalid access token: {e}")))?
+        .claims;
+
+    if let Some(expires_in) = expires_in {
+        let exp = claims.get("exp").and_then(|x| x.as_u64()).unwrap_or_default();
+        let expected = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() + expires_in.as_secs();
+        if exp.abs_diff(expected) > EXP_LEEWAY {
+            return Err(verification_error(format!("exp {exp} does not match expires_in {}", expires_in.as_secs())));
+        }
+    }
+
+    if let Some(subject) = subject {
+        let claim = &verify_config.subject_claim;
+        match claims.get(claim).and_then(|x| x.as_str()) {
+            Some(x) if x == subject => {}
+            x => return Err(verification_error(format!("{claim} {x:?} does not match {subject}"))),
+        }
+    }
+
+    Ok(claims)
+}
+
+/// Read the claims of a JWT without checking its signature.
+///
+/// Only for information the token's holder could change anyway, like when
+/// it expires. Returns `None` for opaque tokens.
+pub fn unverified_claims(token: &str) -> Option<Map<String, Value>> {
+    jsonwebtoken::dangerous::insecure_decode_claims(token).ok()
+}
+
+/// Work out an access token's lifetime and expiry time, in seconds.
+///
+/// `expires_in` from the token response is used if the issuer sent it, then
+/// the `exp` (and `iat`) claims if the token is a JWT, then `default_lifetime`.
+pub fn token_expiry(access_token: &str, expires_in: Option<Duration>, default_lifetime: Duration, now: SystemTime) -> (u64, f64) {
+    let now = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    if let Some(expires_in) = expires_in {
+        log::info!("  access token lifetime {}s from expires_in", expires_in.as_secs());
+        return (expires_in.as_secs(), (now + expires_in).as_secs_f64());
+    }
+
+    let claims = unverified_claims(access_token).unwrap_or_default();
+    if let Some(exp) = claims.get("exp").and_then(|x| x.as_u64()) {
+        let lifetime = match claims.get("iat").and_then(|x| x.as_u64()) {
+            Some(iat) => {
+                log::info!("  access token lifetime from exp and iat claims");
+                exp.saturating_sub(iat)
+            }
+            None => {
+                log::info!("  access token lifetime from exp claim");
+                exp.saturating_sub(now.as_secs())
+            }
+        };
+        return (lifetime, exp as f64);
+    }
+
+    log::info!("  access token lifetime {}s from default", default_lifetime.as_secs());
+    (default_lifetime.as_secs(), (now + default_lifetime).as_secs_f64())
+}
+
+/// Work out when a refresh token expires, in seconds since the epoch.
+///
+/// `refresh_expires_in` from the token response is used if the issuer sent
+/// it, then the `exp` claim if the refresh token is a JWT. Zero means the
+/// token does not expire, as Keycloak says for offline tokens.
+pub fn refresh_token_expiry(refresh_token: &str, refresh_expires_in: Option<u64>, now: SystemTime) -> Option<f64> {
+    let now = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    match refresh_expires_in {
+        Some(0) => None,
+        Some(x) => Some((now + Duration::from_secs(x)).as_secs_f64()),
+        None => unverified_claims(refresh_token)?.get("exp")?.as_u64().map(|x| x as f64),
+    }
+}
+
+/// Verify an access token from the issuer, if the provider is configured to.
+pub fn check_access_token(
+    info: &ClientInfo,
+    jwks_uri: &str,
+    http_client: &HttpClient,
+    access_token: &str,
+    expires_in: Option<Duration>,
+    subject: Option<&str>,
+) -> Result<(), CredmonError> {
+    let Some(verify_config) = &info.verify_tokens else {
+        return Ok(());
+    };
+    let jwks = fetch_jwks(jwks_uri, http_client)?;
+    verify_access_token(access_token, &jwks, info.issuer_url.as_str(), expires_in, subject, verify_config)?;
+    log::info!("  access token verified");
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+
+    use crate::client_auth::tests::TEST_EC_KEY;
+    use crate::logging::test_logger;
+
+    pub const TEST_JWKS: &str = r#"{"keys":[{"kty":"EC","crv":"P-256","kid":"key1","use":"sig","alg":"ES256",
+        "x":"k-li2tKVafsCcr0ov12GSY5rSBp9EqOBxneeJtB7ayY","y":"GzTIMASw_k2KlIYDDbtrXuRHc5t4bKsVIQSWImtVKfw"}]}"#;
+
+    /// Sign an access token with the test key.
+    pub fn sign_token(claims: &Value) -> String {
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some("key1".into());
+        jsonwebtoken::encode(&header, claims, &EncodingKey::from_ec_pem(TEST_EC_KEY.as_bytes()).unwrap()).unwrap()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[test]
+    fn test_verify_config() {
+        test_logger();
+        let mut config = Config::new();
+        assert_eq!(VerifyConfig::from_config("test", &config).unwrap(), None);
+
+        config.insert("test_VERIFY_ACCESS_TOKENS".into(), "true".into());
+        assert_eq!(VerifyConfig::from_config("test", &config).unwrap().unwrap().subject_claim, "sub");
+
+        config.insert("test_SUBJECT_CLAIM".into(), "preferred_username".into());
+        assert_eq!(VerifyConfig::from_config("test", &config).unwrap().unwrap().subject_claim, "preferred_username");
+    }
+
+    #[test]
+    fn test_token_expiry() {
+        test_logger();
+        let now = SystemTime::now();
+        let secs = now.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let default = Duration::from_secs(600);
+        let token = sign_token(&serde_json::json!({"iss": "https://issuer", "exp": secs + 1200, "iat": secs - 60}));
+
+        let (lifetime, at) = token_expiry(&token, Some(Duration::from_secs(300)), default, now);
+        assert_eq!(lifetime, 300);
+        assert_eq!(at as u64, secs + 300);
+
+        let (lifetime, at) = token_expiry(&token, None, default, now);
+        assert_eq!(lifetime, 1260);
+        assert_eq!(at as u64, secs + 1200);
+
+        let token = sign_token(&serde_json::json!({"iss": "https://issuer", "exp": secs + 1200}));
+        assert_eq!(token_expiry(&token, None, default, now), (1200, (secs + 1200) as f64));
+
+        let (lifetime, at) = token_expiry("opaque-token", None, default, now);
+        assert_eq!(lifetime, 600);
+        assert_eq!(at as u64, secs + 600);
+    }
+
+    #[test]
+    fn test_refresh_token_expiry() {
+        test_logger();
+        let now = SystemTime::now();
+        let secs = now.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let token = sign_token(&serde_json::json!({"exp": secs + 3600}));
+
+        assert_eq!(refresh_token_expiry(&token, Some(1800), now).map(|x| x as u64), Some(secs + 1800));
+        assert_eq!(refresh_token_expiry(&token, None, now), Some((secs + 3600) as f64));
+        assert_eq!(refresh_token_expiry(&token, Some(0), now), None);
+        assert_eq!(refresh_token_expiry("opaque-token", None, now), None);
+    }
+
+    #[test]
+    fn test_verify_access_token() {
+        test_logger();
+        let jwks: JwkSet = serde_json::from_str(TEST_JWKS).unwrap();
+        let verify_config = VerifyConfig { subject_claim: "sub".into() };
+        let expires_in = Some(Duration::from_secs(600));
+        let good = serde_json::json!({"iss": "https://issuer", "sub": "alice", "exp": now() + 600, "iat": now()});
+
+        let token = sign_token(&good);
+        let ret = verify_access_token(&token, &jwks, "https://issuer/", expires_in, Some("alice"), &verify_config).unwrap();
+        assert_eq!(ret.get("sub").unwrap(), "alice");
+        assert!(verify_access_token(&token, &jwks, "https://issuer", None, None, &verify_config).is_ok());
+
+        let ret = verify_access_token(&token, &jwks, "https://other", expires_in, Some("alice"), &verify_config);
+        assert!(ret.unwrap_err().to_string().contains("TokenVerificationError"));
+        assert!(verify_access_token(&token, &jwks, "https://issuer", expires_in, Some("bob"), &verify_config).is_err());
+        assert!(verify_access_token(&token, &jwks, "https://issuer", Some(Duration::from_secs(3600)), None, &verify_config).is_err());
+
+        let mut expired = good.clone();
+        expired["exp"] = (now() - 600).into();
+        assert!(verify_access_token(&sign_token(&expired), &jwks, "https://issuer", None, None, &verify_config).is_err());
+
+        assert!(verify_access_token("opaque-token", &jwks, "https://issuer", None, None, &verify_config).is_err());
+
+        // signed with a key the issuer does not have
+        let hmac = jsonwebtoken::encode(&Header::default(), &good, &EncodingKey::from_secret(b"secret")).unwrap();
+        assert!(verify_access_token(&hmac, &jwks, "https://issuer", None, None, &verify_config).is_err());
+    }
+}
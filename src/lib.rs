@@ -1,6 +1,21 @@
+pub mod backoff;
+pub mod client_auth;
 pub mod config;
 pub mod data;
 pub mod error;
 pub mod exchange;
+pub mod http_client;
+pub mod jwt;
+pub mod local_issuer;
 pub mod logging;
+pub mod mapfile;
+pub mod privileges;
 pub mod refresh;
+pub mod secret_file;
+pub mod socket;
+pub mod status;
+pub mod store;
+#[cfg(test)]
+mod test_server;
+pub mod token_response;
+pub mod vault;
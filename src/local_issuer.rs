@@ -0,0 +1,289 @@
+//! A token issuer run by the credmon itself, signing SciTokens or WLCG
+//! tokens with a site key instead of asking an external issuer.
+
+use jsonwebtoken::jwk::JwkSet;
+use oauth2::basic::BasicTokenType;
+use oauth2::{AccessToken, Scope};
+use rand::Rng;
+use rand::distr::Alphanumeric;
+use serde_json::{Map, Value};
+use std::error::Error;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::client_auth::ClientKey;
+use crate::config::{Config, coerce_to_int, get_str};
+use crate::data::{RefreshFile, write_access_token_to_file};
+use crate::error::CredmonError;
+use crate::secret_file::read_secret;
+use crate::token_response::{IssuerTokenResponse, TokenExtraFields};
+
+/// Default token lifetime, in seconds.
+const TOKEN_LIFETIME: u64 = 1200;
+
+/// Which token profile to follow for claims.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TokenProfile {
+    /// WLCG Common JWT Profiles 1.0.
+    Wlcg,
+    /// SciTokens 2.0.
+    Scitokens,
+}
+
+impl TokenProfile {
+    fn default_audience(&self) -> &'static str {
+        match self {
+            Self::Wlcg => "https://wlcg.cern.ch/jwt/v1/any",
+            Self::Scitokens => "ANY",
+        }
+    }
+}
+
+impl FromStr for TokenProfile {
+    type Err = CredmonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "wlcg" => Ok(Self::Wlcg),
+            "scitokens" | "scitoken" => Ok(Self::Scitokens),
+            _ => Err(CredmonError::ConfigError(format!("unknown token profile {s}, must be wlcg or scitokens"))),
+        }
+    }
+}
+
+/// Settings for a `LOCAL_ISSUER` provider.
+pub struct LocalIssuer {
+    pub issuer: String,
+    key: ClientKey,
+    pub lifetime: Duration,
+    /// Scopes users get, with `{username}` replaced.
+    scope_template: String,
+    audience: String,
+    profile: TokenProfile,
+}
+
+fn require_str<'a>(config: &'a Config, key: &str) -> Result<&'a str, CredmonError> {
+    get_str(config, key)?.ok_or(CredmonError::ConfigError(format!("missing {key} in config")))
+}
+
+impl LocalIssuer {
+    /// Read the issuer settings for a provider.
+    ///
+    /// `<provider>_ISSUER` is the issuer URL put in tokens, and
+    /// `<provider>_LOCAL_ISSUER_KEY_FILE` and `<provider>_LOCAL_ISSUER_SCOPES`
+    /// are required.
+    pub fn from_config(provider_name: &str, config: &Config) -> Result<Self, Box<dyn Error>> {
+        let prefix = format!("{provider_name}_LOCAL_ISSUER");
+        let issuer = require_str(config, &format!("{provider_name}_ISSUER"))?.to_string();
+
+        let key_file = require_str(config, &format!("{prefix}_KEY_FILE"))?;
+        let algorithm = get_str(config, &format!("{prefix}_KEY_ALGORITHM"))?;
+        let key_id = get_str(config, &format!("{prefix}_KEY_ID"))?.map(String::from);
+        let key = ClientKey::from_pem(&read_secret(Path::new(key_file), config)?, algorithm, key_id)?;
+
+        let lifetime = match config.get(&format!("{prefix}_TOKEN_LIFETIME")) {
+            Some(x) => coerce_to_int(x)?,
+            None => TOKEN_LIFETIME,
+        };
+        let profile = match get_str(config, &format!("{prefix}_TOKEN_PROFILE"))? {
+            Some(x) => TokenProfile::from_str(x)?,
+            None => TokenProfile::Wlcg,
+        };
+        let audience = get_str(config, &format!("{prefix}_AUDIENCE"))?
+            .unwrap_or(profile.default_audience())
+            .to_string();
+
+        Ok(Self {
+            issuer,
+            key,
+            lifetime: Duration::from_secs(lifetime),
+            scope_template: require_str(config, &format!("{prefix}_SCOPES"))?.to_string(),
+            audience,
+            profile,
+        })
+    }
+
+    /// The scopes to put in a token for `username`.
+    ///
+    /// Users get the scopes from the template, or the ones they asked for,
+    /// if those are all in the template.
+    pub fn scopes_for(&self, username: &str, requested: &str) -> Result<Vec<String>, CredmonError> {
+        let allowed: Vec<String> = self
+            .scope_template
+            .replace("{username}", username)
+            .split_whitespace()
+            .map(String::from)
+            .collect();
+        if requested.trim().is_empty() {
+            return Ok(allowed);
+        }
+        let requested: Vec<String> = requested.split_whitespace().map(String::from).collect();
+        if let Some(x) = requested.iter().find(|x| !allowed.contains(x)) {
+            return Err(CredmonError::ArgumentError(format!("scope {x} is not allowed for {username}")));
+        }
+        Ok(requested)
+    }
+
+    /// The claims of a token for `username`, issued at `now`.
+    pub fn claims(&self, username: &str, scopes: &[String], now: SystemTime) -> Map<String, Value> {
+        let iat = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut claims = Map::new();
+        claims.insert("iss".into(), self.issuer.clone().into());
+        claims.insert("sub".into(), username.into());
+        claims.insert("aud".into(), self.audience.clone().into());
+        claims.insert("iat".into(), iat.into());
+        claims.insert("nbf".into(), iat.into());
+        claims.insert("exp".into(), (iat + self.lifetime.as_secs()).into());
+        claims.insert(
+            "jti".into(),
+            rand::rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect::<String>().into(),
+        );
+        claims.insert("scope".into(), scopes.join(" ").into());
+        match self.profile {
+            TokenProfile::Wlcg => claims.insert("wlcg.ver".into(), "1.0".into()),
+            TokenProfile::Scitokens => claims.insert("ver".into(), "scitoken:2.0".into()),
+        };
+        claims
+    }
+
+    /// Sign a token for `username`, as if an issuer had returned it.
+    pub fn issue(&self, username: &str, requested_scopes: &str, now: SystemTime) -> Result<IssuerTokenResponse, CredmonError> {
+        let scopes = self.scopes_for(username, requested_scopes)?;
+        let token = self.key.sign(&self.claims(username, &scopes, now))?;
+
+        let mut response = IssuerTokenResponse::new(AccessToken::new(token), BasicTokenType::Bearer, TokenExtraFields::default());
+        response.set_expires_in(Some(&self.lifetime));
+        response.set_scopes(Some(scopes.into_iter().map(Scope::new).collect()));
+        Ok(response)
+    }
+
+    /// The public keys resource servers need to trust our tokens.
+    pub fn jwks(&self) -> Result<JwkSet, CredmonError> {
+        Ok(JwkSet { keys: vec![self.key.jwk()?] })
+    }
+}
+
+/// Issue a new access token for the credential at `path`.
+///
+/// The token is for the user owning the credential directory, with the
+/// scopes recorded when the credential was stored.
+pub fn write_local_token(path: &Path, provider_name: &str, config: &Config) -> Result<(), Box<dyn Error>> {
+    let username = path
+        .parent()
+        .and_then(|x| x.file_name())
+        .and_then(|x| x.to_str())
+        .ok_or(CredmonError::OAuthDirError(format!("no user for {}", path.to_str().unwrap_or_default())))?;
+    let refresh_file = RefreshFile::from_file(path)?;
+    let issuer = LocalIssuer::from_config(provider_name, config)?;
+
+    log::info!("  Issuing local token for {username}");
+    let response = issuer.issue(username, &refresh_file.scopes, SystemTime::now())?;
+    write_access_token_to_file(path, &response, Vec::new(), issuer.lifetime)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{DecodingKey, Validation};
+    use oauth2::TokenResponse;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+
+    use crate::client_auth::tests::TEST_EC_KEY;
+    use crate::data::AccessFile;
+    use crate::jwt::unverified_claims;
+    use crate::logging::test_logger;
+
+    fn test_config(key_path: &Path) -> Config {
+        let mut config = Config::new();
+        config.insert("local_ISSUER".into(), "https://credmon.example.org".into());
+        config.insert("local_LOCAL_ISSUER_KEY_FILE".into(), key_path.to_str().into());
+        config.insert("local_LOCAL_ISSUER_KEY_ID".into(), "key1".into());
+        config.insert(
+            "local_LOCAL_ISSUER_SCOPES".into(),
+            "storage.read:/home/{username} storage.create:/home/{username}".into(),
+        );
+        config
+    }
+
+    fn write_key(dir: &Path) -> std::path::PathBuf {
+        let key_path = dir.join("issuer.pem");
+        fs::write(&key_path, TEST_EC_KEY).unwrap();
+        fs::set_permissions(&key_path, fs::Permissions::from_mode(0o600)).unwrap();
+        key_path
+    }
+
+    #[test]
+    fn test_scopes_for() {
+        test_logger();
+        let tmp_dir = tempdir().unwrap();
+        let issuer = LocalIssuer::from_config("local", &test_config(&write_key(tmp_dir.path()))).unwrap();
+
+        assert_eq!(
+            issuer.scopes_for("alice", "").unwrap(),
+            ["storage.read:/home/alice", "storage.create:/home/alice"]
+        );
+        assert_eq!(issuer.scopes_for("alice", "storage.read:/home/alice").unwrap(), ["storage.read:/home/alice"]);
+        assert!(issuer.scopes_for("alice", "storage.read:/home/bob").is_err());
+        assert!(issuer.scopes_for("alice", "storage.read:/").is_err());
+    }
+
+    #[test]
+    fn test_issue() {
+        test_logger();
+        let tmp_dir = tempdir().unwrap();
+        let mut config = test_config(&write_key(tmp_dir.path()));
+        let issuer = LocalIssuer::from_config("local", &config).unwrap();
+        let now = SystemTime::now();
+
+        let response = issuer.issue("alice", "", now).unwrap();
+        assert_eq!(response.expires_in(), Some(Duration::from_secs(TOKEN_LIFETIME)));
+
+        // resource servers can check it with the published keys
+        let jwks = issuer.jwks().unwrap();
+        let key = DecodingKey::from_jwk(&jwks.keys[0]).unwrap();
+        let mut validation = Validation::new(jsonwebtoken::Algorithm::ES256);
+        validation.set_issuer(&["https://credmon.example.org"]);
+        validation.set_audience(&["https://wlcg.cern.ch/jwt/v1/any"]);
+        let claims = jsonwebtoken::decode::<Map<String, Value>>(response.access_token().secret(), &key, &validation)
+            .unwrap()
+            .claims;
+        assert_eq!(claims["sub"], "alice");
+        assert_eq!(claims["scope"], "storage.read:/home/alice storage.create:/home/alice");
+        assert_eq!(claims["wlcg.ver"], "1.0");
+        assert_eq!(claims["exp"].as_u64().unwrap() - claims["iat"].as_u64().unwrap(), TOKEN_LIFETIME);
+
+        config.insert("local_LOCAL_ISSUER_TOKEN_PROFILE".into(), "scitokens".into());
+        config.insert("local_LOCAL_ISSUER_TOKEN_LIFETIME".into(), "600".into());
+        let issuer = LocalIssuer::from_config("local", &config).unwrap();
+        let claims = unverified_claims(issuer.issue("alice", "", now).unwrap().access_token().secret()).unwrap();
+        assert_eq!(claims["ver"], "scitoken:2.0");
+        assert_eq!(claims["aud"], "ANY");
+        assert!(!claims.contains_key("wlcg.ver"));
+        assert_eq!(claims["exp"].as_u64().unwrap() - claims["iat"].as_u64().unwrap(), 600);
+    }
+
+    #[test]
+    fn test_write_local_token() {
+        test_logger();
+        let tmp_dir = tempdir().unwrap();
+        let config = test_config(&write_key(tmp_dir.path()));
+        let user_dir = tmp_dir.path().join("alice");
+        fs::create_dir(&user_dir).unwrap();
+        let path = user_dir.join("local.top");
+        RefreshFile {
+            scopes: "storage.read:/home/alice".into(),
+            ..Default::default()
+        }
+        .write_to_file(&path)
+        .unwrap();
+
+        write_local_token(&path, "local", &config).unwrap();
+        let access = AccessFile::from_file(path.with_extension("use")).unwrap();
+        assert_eq!(access.scope, ["storage.read:/home/alice"]);
+        assert_eq!(access.expires_in, TOKEN_LIFETIME);
+        assert_eq!(unverified_claims(&access.access_token).unwrap()["sub"], "alice");
+    }
+}
@@ -98,9 +98,7 @@ fn log_to_file(condor_config: &ConfigType) -> Result<log4rs::Handle, Box<dyn Err
     Ok(handle)
 }
 
-fn log_to_stderr(condor_config: &ConfigType) -> Result<log4rs::Handle, Box<dyn Error>> {
-    let log_verbosity = get_log_level(condor_config);
-
+fn log_to_stderr(log_verbosity: log::LevelFilter) -> Result<log4rs::Handle, Box<dyn Error>> {
     // Build a stderr logger.
     let stderr = ConsoleAppender::builder()
         .encoder(Box::new(PatternEncoder::new(LOG_FORMAT)))
@@ -120,11 +118,16 @@ pub fn configure_logging(how_output: Option<&str>) -> Result<log4rs::Handle, Box
     let config = condor_config();
 
     match how_output {
-        Some("stderr") => log_to_stderr(&config),
+        Some("stderr") => log_to_stderr(get_log_level(&config)),
         _ => log_to_file(&config),
     }
 }
 
+/// Log everything to stderr, regardless of the configured debug level.
+pub fn configure_debug_logging() -> Result<log4rs::Handle, Box<dyn Error>> {
+    log_to_stderr(log::LevelFilter::Debug)
+}
+
 pub fn update_file_logging(handle: &mut Handle) -> Result<(), Box<dyn Error>> {
     let config = condor_config();
     handle.set_config(log_to_file_setup(&config)?);
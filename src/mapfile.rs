@@ -0,0 +1,169 @@
+//! Map unix usernames to issuer identities.
+//!
+//! The format follows HTCondor's `CERTIFICATE_MAPFILE`: each line is
+//! `METHOD PRINCIPAL CANONICAL`, where METHOD is a provider name or `*`,
+//! PRINCIPAL is a unix username or a `/regex/` (optionally `/regex/i`),
+//! and CANONICAL is the issuer identity, which may use `\1` style
+//! references to regex groups. The first matching line wins.
+
+use regex::{Regex, RegexBuilder};
+use std::fs;
+use std::path::Path;
+
+use crate::config::Config;
+use crate::error::CredmonError;
+
+#[derive(Debug)]
+enum Principal {
+    Literal(String),
+    Pattern(Regex),
+}
+
+#[derive(Debug)]
+struct MapRule {
+    method: String,
+    principal: Principal,
+    canonical: String,
+}
+
+#[derive(Debug, Default)]
+pub struct MapFile {
+    rules: Vec<MapRule>,
+}
+
+fn parse_principal(principal: &str) -> Result<Principal, CredmonError> {
+    let Some(rest) = principal.strip_prefix('/') else {
+        return Ok(Principal::Literal(principal.to_string()));
+    };
+    let (pattern, flags) = rest
+        .rsplit_once('/')
+        .ok_or(CredmonError::ConfigError(format!("unterminated regex {principal}")))?;
+    let regex = RegexBuilder::new(&format!("^(?:{pattern})$"))
+        .case_insensitive(flags.contains('i'))
+        .build()
+        .map_err(|e| CredmonError::ConfigError(format!("bad regex {principal}: {e}")))?;
+    Ok(Principal::Pattern(regex))
+}
+
+/// Replace `\N` in `canonical` with regex groups.
+fn expand(canonical: &str, captures: &regex::Captures) -> String {
+    let mut ret = String::new();
+    let mut chars = canonical.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek().and_then(|x| x.to_digit(10))) {
+            ('\\', Some(n)) => {
+                chars.next();
+                ret += captures.get(n as usize).map_or("", |x| x.as_str());
+            }
+            _ => ret.push(c),
+        }
+    }
+    ret
+}
+
+impl MapFile {
+    pub fn parse(contents: &str) -> Result<Self, CredmonError> {
+        let mut rules = Vec::new();
+        for (num, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let [method, principal, canonical] = parts[..] else {
+                return Err(CredmonError::ConfigError(format!("mapfile line {}: expected 3 fields", num + 1)));
+            };
+            rules.push(MapRule {
+                method: method.to_string(),
+                principal: parse_principal(principal)?,
+                canonical: canonical.to_string(),
+            });
+        }
+        Ok(Self { rules })
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self::parse(&fs::read_to_string(path)?)?)
+    }
+
+    /// Load the mapfile for a provider, if one is configured.
+    ///
+    /// `<provider>_USER_MAPFILE` takes precedence over `CREDMON_OAUTH_USER_MAPFILE`.
+    pub fn for_provider(provider: &str, config: &Config) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let value = config
+            .get(&format!("{provider}_USER_MAPFILE"))
+            .or_else(|| config.get("CREDMON_OAUTH_USER_MAPFILE"));
+        match value {
+            None => Ok(None),
+            Some(x) => {
+                let path = x.as_str().ok_or(CredmonError::ConfigError("USER_MAPFILE is not a string".into()))?;
+                Ok(Some(Self::from_file(path)?))
+            }
+        }
+    }
+
+    /// Map a unix username for a provider, or `None` if no line matches.
+    pub fn map(&self, provider: &str, username: &str) -> Option<String> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.method == "*" || rule.method == provider)
+            .find_map(|rule| match &rule.principal {
+                Principal::Literal(x) if x == username => Some(rule.canonical.clone()),
+                Principal::Literal(_) => None,
+                Principal::Pattern(re) => re.captures(username).map(|c| expand(&rule.canonical, &c)),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::test_logger;
+
+    #[test]
+    fn test_map() {
+        test_logger();
+        let contents = r"
+# comment
+myprovider alice asmith
+*          /(.*)_robot/  robot-\1@example.org
+*          /ADMIN/i      root
+*          /(.*)/        \1@example.org
+";
+        let map = MapFile::parse(contents).unwrap();
+        assert_eq!(map.map("myprovider", "alice"), Some("asmith".into()));
+        assert_eq!(map.map("other", "alice"), Some("alice@example.org".into()));
+        assert_eq!(map.map("other", "build_robot"), Some("robot-build@example.org".into()));
+        assert_eq!(map.map("other", "Admin"), Some("root".into()));
+        // regexes must match the whole name
+        assert_eq!(map.map("other", "xadmin"), Some("xadmin@example.org".into()));
+
+        let map = MapFile::parse("myprovider alice asmith").unwrap();
+        assert_eq!(map.map("myprovider", "bob"), None);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        test_logger();
+        assert!(MapFile::parse("* alice").is_err());
+        assert!(MapFile::parse("* /alice asmith").is_err());
+        assert!(MapFile::parse("* /(/ asmith").is_err());
+    }
+
+    #[test]
+    fn test_for_provider() {
+        test_logger();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, b"* alice asmith\n").unwrap();
+
+        let mut config = Config::new();
+        assert!(MapFile::for_provider("test", &config).unwrap().is_none());
+
+        config.insert("CREDMON_OAUTH_USER_MAPFILE".into(), file.path().to_str().into());
+        let map = MapFile::for_provider("test", &config).unwrap().unwrap();
+        assert_eq!(map.map("test", "alice"), Some("asmith".into()));
+
+        config.insert("test_USER_MAPFILE".into(), "/does/not/exist".into());
+        assert!(MapFile::for_provider("test", &config).is_err());
+    }
+}
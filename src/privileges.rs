@@ -0,0 +1,148 @@
+//! Privilege handling for the setuid storer client.
+//!
+//! The client runs with root's effective uid so it can read client secrets
+//! and write into the credential directory. Everything else, like loading
+//! the HTCondor config, runs as the calling user.
+
+use nix::unistd::{Uid, geteuid, getuid, seteuid};
+use std::env;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+
+use crate::error::CredmonError;
+
+/// `PATH` for child processes of a setuid client.
+const SAFE_PATH: &str = "/usr/bin:/bin";
+
+/// Variables kept from the caller's environment.
+const KEEP_VARS: [&str; 3] = ["LANG", "TERM", "TZ"];
+
+/// Set once root privileges have been dropped, and can be regained.
+static DROPPED: AtomicBool = AtomicBool::new(false);
+
+/// True if running setuid, with an effective uid different from the caller's.
+pub fn is_setuid() -> bool {
+    getuid() != geteuid()
+}
+
+/// The environment a setuid process should run with.
+///
+/// Anything that could change which config, python modules, or shared
+/// libraries are used (`CONDOR_CONFIG`, `_CONDOR_*`, `PYTHON*`, `LD_*`, ...)
+/// is dropped, and `PATH` is reset.
+pub fn sanitized_env(vars: impl Iterator<Item = (String, String)>) -> Vec<(String, String)> {
+    let mut ret: Vec<(String, String)> = vars.filter(|(k, _)| KEEP_VARS.contains(&k.as_str()) || k.starts_with("LC_")).collect();
+    ret.push(("PATH".into(), SAFE_PATH.into()));
+    ret
+}
+
+/// Replace the environment with a sanitized one.
+///
+/// This must be called at the start of `main`, before any threads exist.
+pub fn sanitize_env() {
+    let keep = sanitized_env(env::vars());
+    for (key, _) in env::vars_os() {
+        // SAFETY: single threaded, per the contract above
+        unsafe { env::remove_var(key) };
+    }
+    for (key, value) in keep {
+        // SAFETY: single threaded, per the contract above
+        unsafe { env::set_var(key, value) };
+    }
+}
+
+/// Refuse to run setuid in contexts the storer is never used in.
+pub fn check_context(argv: &[String]) -> Result<(), CredmonError> {
+    if !is_setuid() {
+        return Ok(());
+    }
+    if geteuid() != Uid::from_raw(0) {
+        return Err(CredmonError::GenericError("setuid client must be owned by root".into()));
+    }
+    if getuid() == Uid::from_raw(0) {
+        return Err(CredmonError::GenericError("refusing to store credentials for root".into()));
+    }
+    if argv.len() != 2 {
+        return Err(CredmonError::ArgumentError("expected exactly one argument".into()));
+    }
+    Ok(())
+}
+
+/// Switch the effective uid to the calling user, keeping root as the saved uid.
+pub fn drop_privileges() -> Result<(), CredmonError> {
+    if !is_setuid() {
+        return Ok(());
+    }
+    seteuid(getuid()).map_err(|e| CredmonError::GenericError(format!("cannot drop privileges: {e}")))?;
+    DROPPED.store(true, Relaxed);
+    Ok(())
+}
+
+/// Held while running as root, and drops privileges again when it goes
+/// out of scope, so a panic can't leave the client running as root.
+struct Elevated;
+
+impl Elevated {
+    /// Drop privileges, reporting failure instead of aborting.
+    fn restore(self) -> Result<(), CredmonError> {
+        std::mem::forget(self);
+        seteuid(getuid()).map_err(|e| CredmonError::GenericError(format!("cannot drop privileges: {e}")))
+    }
+}
+
+impl Drop for Elevated {
+    fn drop(&mut self) {
+        // only reached while unwinding, and staying root is worse than dying
+        if seteuid(getuid()).is_err() {
+            std::process::abort();
+        }
+    }
+}
+
+/// Run `f` as root, if privileges were dropped, then drop them again.
+pub fn with_privileges<T>(f: impl FnOnce() -> T) -> Result<T, CredmonError> {
+    if !DROPPED.load(Relaxed) {
+        return Ok(f());
+    }
+    seteuid(Uid::from_raw(0)).map_err(|e| CredmonError::GenericError(format!("cannot regain privileges: {e}")))?;
+    let elevated = Elevated;
+    let ret = f();
+    elevated.restore()?;
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::test_logger;
+
+    #[test]
+    fn test_sanitized_env() {
+        test_logger();
+        let vars = [
+            ("PATH", "/home/user/bin:/usr/bin"),
+            ("PYTHONPATH", "/tmp/evil"),
+            ("PYTHONSTARTUP", "/tmp/evil.py"),
+            ("LD_PRELOAD", "/tmp/evil.so"),
+            ("CONDOR_CONFIG", "/tmp/condor_config"),
+            ("_CONDOR_myprovider_ISSUER", "https://evil"),
+            ("HOME", "/home/user"),
+            ("LANG", "en_US.UTF-8"),
+            ("LC_ALL", "C"),
+        ];
+        let ret = sanitized_env(vars.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+        let keys: Vec<&str> = ret.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, ["LANG", "LC_ALL", "PATH"]);
+        assert!(ret.contains(&("PATH".into(), SAFE_PATH.into())));
+    }
+
+    #[test]
+    fn test_not_setuid() {
+        test_logger();
+        // tests do not run setuid, so these are no-ops
+        assert!(!is_setuid());
+        assert!(check_context(&[]).is_ok());
+        drop_privileges().unwrap();
+        assert_eq!(with_privileges(|| 5).unwrap(), 5);
+    }
+}
@@ -1,14 +1,54 @@
-use oauth2::RefreshToken;
-use openidconnect::core::{CoreClient, CoreProviderMetadata};
-use std::fs;
-use std::path::Path;
-use std::time::{SystemTime, UNIX_EPOCH};
-
-use crate::config::{coerce_to_int, config as condor_config};
-use crate::data::{AccessFile, ClientInfo, RefreshFile, write_tokens_to_file};
+use nix::fcntl::{Flock, FlockArg};
+use oauth2::basic::{BasicErrorResponse, BasicErrorResponseType};
+use oauth2::{RefreshToken, RequestTokenError, Scope, TokenResponse};
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::backoff::{Readiness, RefreshScheduler, RetryState};
+use crate::config::{Config, coerce_to_int, config as condor_config};
+use crate::data::{
+    AccessFile, AccessProfile, ClientInfo, ProviderType, RefreshFile, StateFile, to_epoch, write_access_token_to_file, write_profile_tokens,
+    write_tokens_to_file,
+};
 use crate::error::CredmonError;
+use crate::exchange::{TokenClient, discover_provider, do_client_credentials, token_client, token_endpoint};
+use crate::http_client::{HttpClient, token_request_error};
+use crate::jwt::check_access_token;
+use crate::local_issuer::{LocalIssuer, write_local_token};
+use crate::token_response::IssuerTokenResponse;
+use crate::vault::{VaultConfig, VaultFile, do_vault_refresh};
 
 const TOKEN_MINIMUM_EXPIRATION: u64 = 60;
+const SWEEP_DELAY: u64 = 3600;
+/// Warn about refresh tokens expiring within a week, by default.
+const REFRESH_EXPIRY_WARNING: u64 = 7 * 86400;
+/// Kill the expiry hook if it runs longer than this, by default.
+const EXPIRY_HOOK_TIMEOUT: u64 = 30;
+
+/// Lock a credential while storing or refreshing it, so two refreshes
+/// can't use the same refresh token and a refresh can't overwrite a
+/// credential that was just stored.
+///
+/// This is a `flock` on a `.lock` file next to the `.top` file, so the
+/// daemon, the storer client, and the admin tool wait for each other.
+/// Every call opens the file again, so threads of one process wait too.
+pub fn lock_credential(path: &Path) -> Result<Flock<File>, Box<dyn std::error::Error>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(path.with_extension("lock"))?;
+    Flock::lock(file, FlockArg::LockExclusive)
+        .map_err(|(_, e)| Box::new(CredmonError::GenericError(format!("cannot lock {}: {e}", path.to_str().unwrap()))) as Box<dyn std::error::Error>)
+}
 
 fn is_access_expired(path: &Path, exp_min: u64) -> bool {
     match AccessFile::from_file(path) {
@@ -30,69 +70,319 @@ fn is_access_expired(path: &Path, exp_min: u64) -> bool {
     true
 }
 
+/// True if the credential's refresh token is known to have expired.
+pub fn is_refresh_token_expired(path: &Path, now: SystemTime) -> bool {
+    let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+    RefreshFile::from_file(path).ok().and_then(|x| x.refresh_expires_at).is_some_and(|t| t <= now)
+}
+
 pub fn should_refresh(refresh_path: &Path) -> Result<bool, Box<dyn std::error::Error>> {
-    let config = condor_config();
+    should_refresh_with_config(refresh_path, &condor_config())
+}
 
-    let exp_min = match config.get("CREDMON_OAUTH_TOKEN_MINIMUM") {
-        Some(x) => coerce_to_int(x)?,
-        None => TOKEN_MINIMUM_EXPIRATION,
+/// How long access tokens must still be valid, in seconds.
+pub fn token_minimum(config: &Config) -> Result<u64, Box<dyn std::error::Error>> {
+    match config.get("CREDMON_OAUTH_TOKEN_MINIMUM") {
+        Some(x) => coerce_to_int(x),
+        None => Ok(TOKEN_MINIMUM_EXPIRATION),
+    }
+}
+
+pub fn should_refresh_with_config(refresh_path: &Path, config: &Config) -> Result<bool, Box<dyn std::error::Error>> {
+    let exp_min = token_minimum(config)?;
+
+    if is_access_expired(&refresh_path.with_extension("use"), exp_min) {
+        return Ok(true);
+    }
+    // profile tokens are refreshed along with the main one
+    let profiles = RefreshFile::from_file(refresh_path).map(|x| x.profiles).unwrap_or_default();
+    Ok(profiles.iter().any(|x| is_access_expired(&x.path_for(refresh_path), exp_min)))
+}
+
+/// Split a credential name (the file stem) into provider and handle.
+///
+/// Provider names may contain underscores themselves, so the split is
+/// only made if the part before the last underscore is a configured provider,
+/// with an issuer or, for providers without one, a provider type.
+pub fn split_provider(name: &str, config: &Config) -> (String, Option<String>) {
+    if let Some((p, h)) = name.rsplit_once('_')
+        && (config.contains_key(&format!("{p}_ISSUER")) || config.contains_key(&format!("{p}_PROVIDER_TYPE")))
+    {
+        return (p.to_string(), Some(h.to_string()));
+    }
+    (name.to_string(), None)
+}
+
+fn client_info_for(path: &Path, config: &Config) -> Result<(ClientInfo, ProviderType), Box<dyn std::error::Error>> {
+    let provider_name = path.file_stem().unwrap().to_str().unwrap();
+    log::info!("  provider(+handle) = {provider_name}");
+    let (provider, _) = split_provider(provider_name, config);
+    Ok((ClientInfo::new(&provider, config)?, ProviderType::from_config(&provider, config)?))
+}
+
+/// The provider, if it has no client registration, so the credential
+/// can't go through [`client_info_for`].
+fn unregistered_provider(path: &Path, config: &Config) -> Option<(String, ProviderType)> {
+    let (provider, _) = split_provider(path.file_stem()?.to_str()?, config);
+    match ProviderType::from_config(&provider, config).ok()? {
+        x @ (ProviderType::LocalIssuer | ProviderType::Vault) => Some((provider, x)),
+        _ => None,
+    }
+}
+
+/// The configured Vault server of a vault provider, to pause refreshes while it is down.
+fn vault_server(provider: &str, provider_type: ProviderType, config: &Config) -> Option<String> {
+    if provider_type != ProviderType::Vault {
+        return None;
+    }
+    VaultConfig::from_config(provider, config).ok().map(|x| x.server)
+}
+
+/// Get a new access token for a credential without a client registration, recording the result.
+fn unregistered_refresh(
+    path: &Path,
+    provider: &str,
+    provider_type: ProviderType,
+    config: &Config,
+    scheduler: &mut RefreshScheduler,
+    now: SystemTime,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let server = vault_server(provider, provider_type, config);
+    let result = match provider_type {
+        ProviderType::Vault => VaultConfig::from_config(provider, config).and_then(|x| do_vault_refresh(path, &x, token_minimum(config)?)),
+        _ => write_local_token(path, provider, config),
     };
+    match result {
+        Ok(_) => {
+            scheduler.record_success(path, server.as_deref(), now);
+            Ok(())
+        }
+        Err(e) => {
+            scheduler.record_failure(path, server.as_deref(), e.as_ref(), now);
+            Err(e)
+        }
+    }
+}
 
-    Ok(is_access_expired(&refresh_path.with_extension("use"), exp_min))
+/// Convert a refresh request error.
+///
+/// `invalid_grant` means the refresh token is no good any more: it expired,
+/// was revoked, or was already used, for issuers that rotate refresh tokens
+/// and detect reuse. Retrying won't help, the user must store a new one.
+fn refresh_request_error<RE>(e: RequestTokenError<RE, BasicErrorResponse>) -> CredmonError
+where
+    RE: std::error::Error + 'static,
+{
+    if let RequestTokenError::ServerResponse(x) = &e
+        && *x.error() == BasicErrorResponseType::InvalidGrant
+    {
+        if x.error_description().is_some_and(|x| x.to_ascii_lowercase().contains("reuse")) {
+            log::warn!("  Issuer detected refresh token reuse, was a copy of this credential used elsewhere?");
+        }
+        return CredmonError::RefreshTokenRevoked(x.to_string());
+    }
+    token_request_error(e)
 }
 
-fn single_refresh(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
-    log::info!("Checking {}", path.to_str().unwrap());
-    let config = condor_config();
+/// The issuer's token endpoint, for refreshing one credential's tokens.
+struct RefreshContext {
+    http_client: HttpClient,
+    client: TokenClient,
+    token_url: String,
+    jwks_uri: String,
+}
 
-    if !should_refresh(path)? {
+impl RefreshContext {
+    fn new(info: &ClientInfo) -> Result<Self, Box<dyn std::error::Error>> {
+        let http_client = HttpClient::for_client(info)?;
+        let provider_metadata = discover_provider(info, &http_client)?;
+        Ok(Self {
+            client: token_client(info, &provider_metadata)?,
+            token_url: token_endpoint(&provider_metadata)?,
+            jwks_uri: provider_metadata.jwks_uri().to_string(),
+            http_client,
+        })
+    }
+
+    /// Refresh, optionally narrowing the scopes or picking an audience,
+    /// and check the new access token is for `subject`.
+    fn refresh(
+        &self,
+        info: &ClientInfo,
+        refresh_token: &str,
+        profile: Option<&AccessProfile>,
+        subject: Option<&str>,
+    ) -> Result<IssuerTokenResponse, Box<dyn std::error::Error>> {
+        let refresh_token = RefreshToken::new(refresh_token.to_string());
+        let mut request = self.client.exchange_refresh_token(&refresh_token);
+        if let Some(profile) = profile {
+            for scope in profile.scopes.split_whitespace() {
+                request = request.add_scope(Scope::new(scope.to_string()));
+            }
+            if let Some(audience) = &profile.audience {
+                request = request.add_extra_param("audience", audience.clone());
+            }
+        }
+        // client assertions are single use, so sign a new one every time
+        for (key, value) in info.client_auth.extra_params(info.client_id.as_str(), &self.token_url)? {
+            request = request.add_extra_param(key, value);
+        }
+        let token_response = request.request(&self.http_client).map_err(refresh_request_error)?;
+        check_access_token(
+            info,
+            &self.jwks_uri,
+            &self.http_client,
+            token_response.access_token().secret(),
+            token_response.expires_in(),
+            subject,
+        )?;
+        Ok(token_response)
+    }
+}
+
+/// Get access tokens for the credential's profiles.
+///
+/// The main access token is already written, so a failing profile is only
+/// logged, and doesn't count against the credential or hold up the others.
+fn refresh_profiles(path: &Path, info: &ClientInfo, context: &RefreshContext) -> Result<(), Box<dyn std::error::Error>> {
+    let refresh_file = RefreshFile::from_file(path)?;
+    for profile in &refresh_file.profiles {
+        // a credential stored with a handle matching the profile owns that file
+        if profile.path_for(path).with_extension("top").exists() {
+            log::warn!("  Skipping profile {}, {} belongs to another credential", profile.name, profile.file);
+            continue;
+        }
+        log::info!("  Getting access token for profile {}", profile.name);
+        if let Err(e) = refresh_profile(path, info, context, profile, refresh_file.subject.as_deref()) {
+            log::error!("  Failed to get access token for profile {}: {e}", profile.name);
+        }
+    }
+    Ok(())
+}
+
+fn refresh_profile(
+    path: &Path,
+    info: &ClientInfo,
+    context: &RefreshContext,
+    profile: &AccessProfile,
+    subject: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // re-read every time, in case the last profile rotated it
+    let refresh_token = RefreshFile::from_file(path)?
+        .refresh_token
+        .ok_or(CredmonError::MissingRefreshToken("no refresh token stored".into()))?;
+    let token_response = context.refresh(info, &refresh_token, Some(profile), subject)?;
+    write_profile_tokens(path, profile, &token_response, info.access_token_lifetime)
+}
+
+/// Get access tokens for the profiles of a newly stored credential.
+pub fn get_profile_tokens(path: &Path, info: &ClientInfo) -> Result<(), Box<dyn std::error::Error>> {
+    if RefreshFile::from_file(path)?.profiles.is_empty() {
         return Ok(());
     }
+    refresh_profiles(path, info, &RefreshContext::new(info)?)
+}
+
+fn do_refresh(path: &Path, info: ClientInfo, provider_type: ProviderType) -> Result<(), Box<dyn std::error::Error>> {
     log::warn!("  Now doing refresh for {}", path.to_str().unwrap());
 
     let old_refresh_file = RefreshFile::from_file(path)?;
+    let lifetime = info.access_token_lifetime;
 
-    let provider_name = path.file_stem().unwrap().to_str().unwrap();
-    log::info!("  provider(+handle) = {provider_name}");
-    let info = match provider_name.rsplit_once('_') {
-        Some((p, _)) => match ClientInfo::new(p, &config) {
-            Err(_) => ClientInfo::new(provider_name, &config)?,
-            Ok(x) => x,
-        },
-        None => ClientInfo::new(provider_name, &config)?,
-    };
-
-    // 1. Discover the provider metadata (or manually configure if known)
-    let http_client = reqwest::blocking::ClientBuilder::new()
-        // Following redirects opens the client up to SSRF vulnerabilities.
-        .redirect(reqwest::redirect::Policy::none())
-        .build()
-        .expect("Client should build");
+    if provider_type == ProviderType::ClientCredentials {
+        let token_response = do_client_credentials(info, &old_refresh_file.scopes)?;
+        let scopes = old_refresh_file.scopes.split_whitespace().map(String::from).collect();
+        return write_access_token_to_file(path, &token_response, scopes, lifetime);
+    }
 
-    let provider_metadata = match CoreProviderMetadata::discover(&info.issuer_url, &http_client) {
-        Ok(x) => Ok(x),
-        Err(x) => Err(CredmonError::DiscoveryError(x.to_string())),
-    }?;
+    let refresh_token = old_refresh_file
+        .refresh_token
+        .ok_or(CredmonError::MissingRefreshToken("no refresh token stored".into()))?;
 
-    let client = CoreClient::from_provider_metadata(provider_metadata, info.client_id, Some(info.client_secret))
-        .set_redirect_uri(openidconnect::RedirectUrl::new("http://localhost".to_string())?); // Redirect URI is required for client creation, but not strictly used in Client Credentials Flow
+    let context = RefreshContext::new(&info)?;
+    let token_response = context.refresh(&info, &refresh_token, None, old_refresh_file.subject.as_deref())?;
+    write_tokens_to_file(path, token_response, old_refresh_file.subject, old_refresh_file.profiles, lifetime)?;
 
-    // 2. Do token refresh
-    let token_response = client
-        .exchange_refresh_token(&RefreshToken::new(old_refresh_file.refresh_token))?
-        .request(&http_client)?;
+    refresh_profiles(path, &info, &context)
+}
 
-    write_tokens_to_file(path, token_response)
+enum RefreshOutcome {
+    NotNeeded,
+    Refreshed,
+    Skipped,
 }
 
-pub fn refresh_all_tokens() -> Result<(), Box<dyn std::error::Error>> {
+fn single_refresh(path: &Path, scheduler: &mut RefreshScheduler) -> Result<RefreshOutcome, Box<dyn std::error::Error>> {
+    log::info!("Checking {}", path.to_str().unwrap());
+    let _lock = lock_credential(path)?;
     let config = condor_config();
 
+    if !should_refresh(path)? {
+        scheduler.record_not_needed(path);
+        return Ok(RefreshOutcome::NotNeeded);
+    }
+
+    let now = SystemTime::now();
+    if is_refresh_token_expired(path, now) {
+        log::warn!("  Refresh token expired, waiting for the user to store a new one");
+        return Ok(RefreshOutcome::Skipped);
+    }
+    if !scheduler.credential_ready(path, now) {
+        log::info!("  Backing off after previous failures");
+        return Ok(RefreshOutcome::Skipped);
+    }
+
+    if let Some((provider, provider_type)) = unregistered_provider(path, &config) {
+        if let Some(server) = vault_server(&provider, provider_type, &config)
+            && !scheduler.issuer_allowed(&server, now)
+        {
+            log::info!("  Refreshes to {server} are paused");
+            return Ok(RefreshOutcome::Skipped);
+        }
+        return unregistered_refresh(path, &provider, provider_type, &config, scheduler, now).map(|_| RefreshOutcome::Refreshed);
+    }
+
+    let (info, provider_type) = match client_info_for(path, &config) {
+        Ok(x) => x,
+        Err(e) => {
+            scheduler.record_failure(path, None, e.as_ref(), now);
+            return Err(e);
+        }
+    };
+
+    let issuer = info.issuer_url.to_string();
+    if !scheduler.issuer_allowed(&issuer, now) {
+        log::info!("  Refreshes to {issuer} are paused");
+        return Ok(RefreshOutcome::Skipped);
+    }
+
+    match do_refresh(path, info, provider_type) {
+        Ok(_) => {
+            scheduler.record_success(path, Some(&issuer), now);
+            Ok(RefreshOutcome::Refreshed)
+        }
+        Err(e) => {
+            scheduler.record_failure(path, Some(&issuer), e.as_ref(), now);
+            Err(e)
+        }
+    }
+}
+
+/// Get the OAuth credential directory from the config.
+pub fn credential_dir(config: &Config) -> Result<PathBuf, Box<dyn std::error::Error>> {
     let cred_dir = config
         .get("SEC_CREDENTIAL_DIRECTORY_OAUTH")
         .ok_or(CredmonError::OAuthDirError("missing SEC_CREDENTIAL_DIRECTORY_OAUTH in config".into()))?
         .as_str()
         .ok_or(CredmonError::OAuthDirError("SEC_CREDENTIAL_DIRECTORY_OAUTH is not a string".into()))?;
+    Ok(PathBuf::from(cred_dir))
+}
+
+/// Find all refresh token files in the credential directory.
+///
+/// Each user has a directory, with a `.top` file per provider(+handle).
+pub fn list_credentials(cred_dir: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut ret = Vec::new();
 
     // iterate over credential directory
     for path in fs::read_dir(cred_dir)? {
@@ -107,27 +397,629 @@ pub fn refresh_all_tokens() -> Result<(), Box<dyn std::error::Error>> {
                     .ok_or(CredmonError::OAuthDirError("Error decoding filename".into()))?
                     .ends_with(".top")
                 {
-                    // this is a refresh token, so let's process it
-                    let path = path.path();
-                    match single_refresh(&path) {
-                        Ok(_) => {}
-                        Err(e) => log::warn!("Error refreshing {}: {e}", path.to_str().unwrap()),
-                    };
+                    // this is a refresh token
+                    ret.push(path.path());
                 }
             }
         }
     }
 
+    ret.sort();
+    Ok(ret)
+}
+
+/// The file credd touches when a user's credentials should be swept.
+pub fn mark_file(cred_dir: &Path, user: &str) -> PathBuf {
+    cred_dir.join(format!("{user}.mark"))
+}
+
+pub fn sweep_delay(config: &Config) -> Result<u64, Box<dyn std::error::Error>> {
+    match config.get("SEC_CREDENTIAL_SWEEP_DELAY") {
+        Some(x) => coerce_to_int(x),
+        None => Ok(SWEEP_DELAY),
+    }
+}
+
+/// Refresh a credential right away, regardless of expiration or backoff.
+pub fn force_refresh(path: &Path, scheduler: &mut RefreshScheduler) -> Result<(), Box<dyn std::error::Error>> {
+    log::info!("Forcing refresh of {}", path.to_str().unwrap());
+    let _lock = lock_credential(path)?;
+    let config = condor_config();
+    let now = SystemTime::now();
+
+    if let Some((provider, provider_type)) = unregistered_provider(path, &config) {
+        return unregistered_refresh(path, &provider, provider_type, &config, scheduler, now);
+    }
+
+    let (info, provider_type) = match client_info_for(path, &config) {
+        Ok(x) => x,
+        Err(e) => {
+            scheduler.record_failure(path, None, e.as_ref(), now);
+            return Err(e);
+        }
+    };
+
+    let issuer = info.issuer_url.to_string();
+    match do_refresh(path, info, provider_type) {
+        Ok(_) => {
+            scheduler.record_success(path, Some(&issuer), now);
+            Ok(())
+        }
+        Err(e) => {
+            scheduler.record_failure(path, Some(&issuer), e.as_ref(), now);
+            Err(e)
+        }
+    }
+}
+
+/// What a refresh pass would do with a credential.
+#[derive(Debug, PartialEq)]
+pub enum PlannedAction {
+    Refresh,
+    NotNeeded,
+    BackingOff(f64),
+    Quarantined,
+    RefreshTokenExpired,
+    ConfigProblem(String),
+}
+
+/// Work out what a refresh pass would do with a credential,
+/// without contacting the issuer or writing any files.
+pub fn plan_refresh(path: &Path, config: &Config, now: SystemTime) -> PlannedAction {
+    if let Err(e) = RefreshFile::from_file(path) {
+        return PlannedAction::ConfigProblem(format!("cannot read refresh token file: {e}"));
+    }
+    let checked = match unregistered_provider(path, config) {
+        Some((provider, ProviderType::Vault)) => VaultConfig::from_config(&provider, config).and_then(|_| VaultFile::from_file(path).map(|_| ())),
+        Some((provider, _)) => LocalIssuer::from_config(&provider, config).map(|_| ()),
+        None => client_info_for(path, config).map(|_| ()),
+    };
+    if let Err(e) = checked {
+        return PlannedAction::ConfigProblem(e.to_string());
+    }
+    match should_refresh_with_config(path, config) {
+        Ok(false) => return PlannedAction::NotNeeded,
+        Ok(true) => {}
+        Err(e) => return PlannedAction::ConfigProblem(e.to_string()),
+    }
+    if is_refresh_token_expired(path, now) {
+        return PlannedAction::RefreshTokenExpired;
+    }
+
+    if let Ok(state) = StateFile::from_file(StateFile::path_for(path)) {
+        match RetryState::from(state).readiness(path, now) {
+            Readiness::Quarantined => return PlannedAction::Quarantined,
+            Readiness::BackingOff(t) => return PlannedAction::BackingOff(to_epoch(t)),
+            Readiness::Ready | Readiness::Replaced => {}
+        }
+    }
+
+    PlannedAction::Refresh
+}
+
+/// Plan a refresh pass over all credentials.
+pub fn plan_all_tokens() -> Result<Vec<(PathBuf, PlannedAction)>, Box<dyn std::error::Error>> {
+    let config = condor_config();
+    let cred_dir = credential_dir(&config)?;
+    let now = SystemTime::now();
+
+    Ok(list_credentials(&cred_dir)?
+        .into_iter()
+        .map(|path| {
+            let action = plan_refresh(&path, &config, now);
+            (path, action)
+        })
+        .collect())
+}
+
+/// A user whose credentials credd has marked for sweeping.
+#[derive(Debug, PartialEq)]
+pub struct MarkedUser {
+    pub user: String,
+    pub marked_at: SystemTime,
+    pub sweep_at: SystemTime,
+}
+
+/// Find all users with a mark file in the credential directory.
+pub fn list_marked_users(cred_dir: &Path, config: &Config) -> Result<Vec<MarkedUser>, Box<dyn std::error::Error>> {
+    let delay = Duration::from_secs(sweep_delay(config)?);
+    let mut ret = Vec::new();
+
+    for path in fs::read_dir(cred_dir)? {
+        let path = path?;
+        let filename = path.file_name();
+        let Some(user) = filename.to_str().and_then(|x| x.strip_suffix(".mark")) else {
+            continue;
+        };
+        // "..mark" must not turn into sweeping the credential directory itself
+        if user.is_empty() || user == "." || user == ".." || user.contains('/') {
+            log::warn!("Ignoring mark file for invalid user {user:?}");
+            continue;
+        }
+        if !path.file_type()?.is_file() {
+            continue;
+        }
+        let marked_at = path.metadata()?.modified()?;
+        ret.push(MarkedUser {
+            user: user.to_string(),
+            marked_at,
+            sweep_at: marked_at + delay,
+        });
+    }
+
+    ret.sort_by(|a, b| a.user.cmp(&b.user));
+    Ok(ret)
+}
+
+/// Counts of what happened during a refresh pass.
+#[derive(Default, Debug, PartialEq)]
+pub struct RefreshSummary {
+    pub checked: usize,
+    pub refreshed: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub swept: usize,
+    pub expiring: usize,
+}
+
+pub fn refresh_all_tokens(scheduler: &mut RefreshScheduler) -> Result<RefreshSummary, Box<dyn std::error::Error>> {
+    let config = condor_config();
+    let cred_dir = credential_dir(&config)?;
+    let mut summary = RefreshSummary::default();
+
+    for path in list_credentials(&cred_dir)? {
+        summary.checked += 1;
+        match single_refresh(&path, scheduler) {
+            Ok(RefreshOutcome::NotNeeded) => {}
+            Ok(RefreshOutcome::Refreshed) => summary.refreshed += 1,
+            Ok(RefreshOutcome::Skipped) => summary.skipped += 1,
+            Err(e) => {
+                summary.failed += 1;
+                log::warn!("Error refreshing {}: {e}", path.to_str().unwrap());
+            }
+        };
+    }
+
+    summary.swept = sweep_marked_users(&cred_dir, &config, SystemTime::now())?.len();
+    summary.expiring = notify_expiring(&cred_dir, &config, scheduler, SystemTime::now())?;
+
+    Ok(summary)
+}
+
+/// How many seconds before a refresh token expires to warn about it,
+/// from `CREDMON_OAUTH_REFRESH_EXPIRY_WARNING`.
+pub fn refresh_expiry_warning(config: &Config) -> Result<u64, Box<dyn std::error::Error>> {
+    match config.get("CREDMON_OAUTH_REFRESH_EXPIRY_WARNING") {
+        Some(x) => coerce_to_int(x),
+        None => Ok(REFRESH_EXPIRY_WARNING),
+    }
+}
+
+/// A credential whose refresh token expires soon, or already has.
+#[derive(Debug, PartialEq)]
+pub struct ExpiringCredential {
+    pub path: PathBuf,
+    pub refresh_expires_at: f64,
+}
+
+/// Find credentials whose refresh tokens expire within `within` seconds of `now`.
+pub fn list_expiring(cred_dir: &Path, within: u64, now: SystemTime) -> Result<Vec<ExpiringCredential>, Box<dyn std::error::Error>> {
+    let cutoff = now.duration_since(UNIX_EPOCH)?.as_secs_f64() + within as f64;
+    Ok(list_credentials(cred_dir)?
+        .into_iter()
+        .filter_map(|path| {
+            let refresh_expires_at = RefreshFile::from_file(&path).ok()?.refresh_expires_at?;
+            (refresh_expires_at <= cutoff).then_some(ExpiringCredential { path, refresh_expires_at })
+        })
+        .collect())
+}
+
+/// How long the expiry hook may run, from `CREDMON_OAUTH_REFRESH_EXPIRY_HOOK_TIMEOUT`.
+fn expiry_hook_timeout(config: &Config) -> Result<Duration, Box<dyn std::error::Error>> {
+    match config.get("CREDMON_OAUTH_REFRESH_EXPIRY_HOOK_TIMEOUT") {
+        Some(x) => Ok(Duration::from_secs(coerce_to_int(x)?)),
+        None => Ok(Duration::from_secs(EXPIRY_HOOK_TIMEOUT)),
+    }
+}
+
+/// Run `CREDMON_OAUTH_REFRESH_EXPIRY_HOOK` for a credential, with the user,
+/// credential name, and expiry time as arguments, killing it after `timeout`.
+fn run_expiry_hook(hook: &str, expiring: &ExpiringCredential, timeout: Duration) -> Result<(), Box<dyn std::error::Error>> {
+    let mut words = hook.split_whitespace();
+    let program = words
+        .next()
+        .ok_or(CredmonError::ConfigError("CREDMON_OAUTH_REFRESH_EXPIRY_HOOK is empty".into()))?;
+    let path = &expiring.path;
+    let user = path.parent().and_then(|x| x.file_name()).and_then(|x| x.to_str()).unwrap_or_default();
+    let name = path.file_stem().and_then(|x| x.to_str()).unwrap_or_default();
+
+    let mut child = Command::new(program)
+        .args(words)
+        .arg(user)
+        .arg(name)
+        .arg((expiring.refresh_expires_at as u64).to_string())
+        .spawn()?;
+    // don't let a stuck hook hold up refreshing
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if start.elapsed() > timeout {
+            child.kill()?;
+            child.wait()?;
+            return Err(Box::new(CredmonError::GenericError(format!(
+                "{program} did not finish within {} seconds",
+                timeout.as_secs()
+            ))));
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    if !status.success() {
+        return Err(Box::new(CredmonError::GenericError(format!("{program} exited with {status}"))));
+    }
     Ok(())
 }
 
+/// Warn once about each refresh token expiring within
+/// `CREDMON_OAUTH_REFRESH_EXPIRY_WARNING` seconds, and run
+/// `CREDMON_OAUTH_REFRESH_EXPIRY_HOOK` for it, if set.
+///
+/// Returns the number of expiring credentials.
+pub fn notify_expiring(cred_dir: &Path, config: &Config, scheduler: &mut RefreshScheduler, now: SystemTime) -> Result<usize, Box<dyn std::error::Error>> {
+    let expiring = list_expiring(cred_dir, refresh_expiry_warning(config)?, now)?;
+    let hook = config.get("CREDMON_OAUTH_REFRESH_EXPIRY_HOOK").and_then(|x| x.as_str());
+    let timeout = expiry_hook_timeout(config)?;
+
+    for x in &expiring {
+        if !scheduler.notify_expiry(&x.path, UNIX_EPOCH + Duration::from_secs_f64(x.refresh_expires_at)) {
+            continue;
+        }
+        log::warn!("Refresh token for {} expires at {}", x.path.to_str().unwrap(), x.refresh_expires_at as u64);
+        if let Some(hook) = hook
+            && let Err(e) = run_expiry_hook(hook, x, timeout)
+        {
+            log::warn!("Error running refresh expiry hook: {e}");
+        }
+    }
+
+    Ok(expiring.len())
+}
+
+/// Delete the credentials of users that credd marked for sweeping
+/// more than `SEC_CREDENTIAL_SWEEP_DELAY` seconds ago.
+///
+/// Returns the users that were swept.
+pub fn sweep_marked_users(cred_dir: &Path, config: &Config, now: SystemTime) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut ret = Vec::new();
+
+    for marked in list_marked_users(cred_dir, config)? {
+        if marked.sweep_at > now {
+            continue;
+        }
+        log::warn!("Sweeping credentials for {}", marked.user);
+        let user_dir = cred_dir.join(&marked.user);
+        if user_dir.exists()
+            && let Err(e) = fs::remove_dir_all(&user_dir)
+        {
+            log::warn!("Error sweeping {}: {e}", user_dir.to_str().unwrap());
+            continue;
+        }
+        if let Err(e) = fs::remove_file(mark_file(cred_dir, &marked.user)) {
+            log::warn!("Error removing mark file for {}: {e}", marked.user);
+        }
+        ret.push(marked.user);
+    }
+
+    Ok(ret)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::time::Duration;
-    use tempfile::NamedTempFile;
+    use std::collections::BTreeMap;
+
+    use crate::data::DEFAULT_ACCESS_TOKEN_LIFETIME;
+    use oauth2::ClientId;
+    use openidconnect::IssuerUrl;
+    use tempfile::{NamedTempFile, tempdir};
+    use zeroize::Zeroizing;
 
+    use crate::client_auth::tests::TEST_EC_KEY;
+    use crate::client_auth::{ClientAuth, ClientKey, SecretMethod};
+    use crate::jwt::VerifyConfig;
+    use crate::jwt::tests::{TEST_JWKS, sign_token};
     use crate::logging::test_logger;
+    use crate::test_server::TestServer;
+
+    fn client_info(server: &TestServer) -> ClientInfo {
+        ClientInfo {
+            issuer_url: IssuerUrl::new(server.url.clone()).unwrap(),
+            client_id: ClientId::new("robot".into()),
+            client_auth: ClientAuth::Secret {
+                secret: Zeroizing::new("secret".into()),
+                method: None,
+            },
+            verify_tokens: None,
+            access_token_lifetime: DEFAULT_ACCESS_TOKEN_LIFETIME,
+        }
+    }
+
+    #[test]
+    fn test_do_refresh_private_key_jwt() {
+        test_logger();
+        let server = TestServer::issuer(200, r#"{"access_token":"at2","refresh_token":"rt2","token_type":"bearer","expires_in":300}"#);
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("provider.top");
+        RefreshFile {
+            refresh_token: Some("rt1".into()),
+            scopes: "read".into(),
+            subject: Some("alice@example.org".into()),
+            ..Default::default()
+        }
+        .write_to_file(&path)
+        .unwrap();
+
+        let info = ClientInfo {
+            client_auth: ClientAuth::PrivateKeyJwt(ClientKey::from_pem(TEST_EC_KEY.as_bytes(), None, None).unwrap()),
+            ..client_info(&server)
+        };
+        do_refresh(&path, info, ProviderType::TokenExchange).unwrap();
+
+        let requests = server.requests_to("/token");
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].body.contains("grant_type=refresh_token"));
+        assert!(requests[0].body.contains("client_assertion=ey"));
+        assert!(!requests[0].headers.contains_key("authorization"));
+
+        let ret = RefreshFile::from_file(&path).unwrap();
+        assert_eq!(ret.refresh_token, Some("rt2".into()));
+        assert_eq!(ret.subject, Some("alice@example.org".into()));
+        assert_eq!(AccessFile::from_file(path.with_extension("use")).unwrap().access_token, "at2");
+    }
+
+    #[test]
+    fn test_do_refresh_verify_tokens() {
+        test_logger();
+        let server = TestServer::issuer(200, "{}");
+        server.route("/certs", 200, TEST_JWKS);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let respond = |sub: &str| {
+            let token = sign_token(&serde_json::json!({"iss": server.url, "sub": sub, "exp": now + 300}));
+            let body = serde_json::json!({"access_token": token, "refresh_token": "rt2", "token_type": "bearer", "expires_in": 300});
+            server.route("/token", 200, &body.to_string());
+        };
+
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("provider.top");
+        RefreshFile {
+            refresh_token: Some("rt1".into()),
+            scopes: "read".into(),
+            subject: Some("alice".into()),
+            ..Default::default()
+        }
+        .write_to_file(&path)
+        .unwrap();
+        let info = || ClientInfo {
+            verify_tokens: Some(VerifyConfig { subject_claim: "sub".into() }),
+            ..client_info(&server)
+        };
+
+        // a token for someone else is not written
+        respond("bob");
+        let ret = do_refresh(&path, info(), ProviderType::TokenExchange);
+        assert!(ret.unwrap_err().to_string().contains("TokenVerificationError"));
+        assert_eq!(RefreshFile::from_file(&path).unwrap().refresh_token, Some("rt1".into()));
+
+        respond("alice");
+        do_refresh(&path, info(), ProviderType::TokenExchange).unwrap();
+        assert_eq!(RefreshFile::from_file(&path).unwrap().refresh_token, Some("rt2".into()));
+    }
+
+    #[test]
+    fn test_do_refresh_client_secret_post() {
+        test_logger();
+        let server = TestServer::issuer(200, r#"{"access_token":"at2","refresh_token":"rt2","token_type":"bearer","expires_in":300}"#);
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("provider.top");
+        RefreshFile {
+            refresh_token: Some("rt1".into()),
+            scopes: "read".into(),
+            ..Default::default()
+        }
+        .write_to_file(&path)
+        .unwrap();
+
+        let info = ClientInfo {
+            client_auth: ClientAuth::Secret {
+                secret: Zeroizing::new("secret".into()),
+                method: Some(SecretMethod::Post),
+            },
+            ..client_info(&server)
+        };
+        do_refresh(&path, info, ProviderType::TokenExchange).unwrap();
+
+        let requests = server.requests_to("/token");
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].body.contains("client_id=robot"));
+        assert!(requests[0].body.contains("client_secret=secret"));
+        assert!(!requests[0].headers.contains_key("authorization"));
+    }
+
+    #[test]
+    fn test_do_refresh_extra_fields() {
+        test_logger();
+        let server = TestServer::issuer(
+            200,
+            r#"{"access_token":"at2","refresh_token":"rt2","token_type":"bearer","expires_in":300,"refresh_expires_in":1800,"session_state":"abcd"}"#,
+        );
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("provider.top");
+        RefreshFile {
+            refresh_token: Some("rt1".into()),
+            scopes: "read".into(),
+            id_token_claims: BTreeMap::from([("preferred_username".into(), "alice".into())]),
+            ..Default::default()
+        }
+        .write_to_file(&path)
+        .unwrap();
+
+        let info = client_info(&server);
+        do_refresh(&path, info, ProviderType::TokenExchange).unwrap();
+
+        // refresh expiry comes from the refresh response, ID token claims from before
+        let refresh_file = RefreshFile::from_file(&path).unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+        assert!((refresh_file.refresh_expires_at.unwrap() - now - 1800.0).abs() < 5.0);
+        assert_eq!(refresh_file.id_token_claims["preferred_username"], "alice");
+    }
+
+    #[test]
+    fn test_do_refresh_rotation() {
+        test_logger();
+        let server = TestServer::issuer(200, r#"{"access_token":"at2","refresh_token":"rt2","token_type":"bearer","expires_in":300}"#);
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("provider.top");
+        RefreshFile {
+            refresh_token: Some("rt1".into()),
+            scopes: "read".into(),
+            refresh_expires_at: Some(12345.0),
+            ..Default::default()
+        }
+        .write_to_file(&path)
+        .unwrap();
+        let info = || client_info(&server);
+        let read_back = || RefreshFile::from_file(&path).unwrap();
+
+        // a rotated refresh token replaces the old one
+        do_refresh(&path, info(), ProviderType::TokenExchange).unwrap();
+        assert!(server.requests_to("/token")[0].body.contains("refresh_token=rt1"));
+        assert_eq!(read_back().refresh_token, Some("rt2".into()));
+        assert_eq!(read_back().refresh_expires_at, None);
+
+        // no refresh token in the response keeps the old one, and its expiry
+        let mut refresh_file = read_back();
+        refresh_file.refresh_expires_at = Some(12345.0);
+        refresh_file.write_to_file(&path).unwrap();
+        server.route("/token", 200, r#"{"access_token":"at3","token_type":"bearer","expires_in":300}"#);
+        do_refresh(&path, info(), ProviderType::TokenExchange).unwrap();
+        assert!(server.requests_to("/token")[1].body.contains("refresh_token=rt2"));
+        assert_eq!(read_back().refresh_token, Some("rt2".into()));
+        assert_eq!(read_back().refresh_expires_at, Some(12345.0));
+        assert_eq!(AccessFile::from_file(path.with_extension("use")).unwrap().access_token, "at3");
+
+        // reuse detection revokes the refresh token
+        server.route(
+            "/token",
+            400,
+            r#"{"error":"invalid_grant","error_description":"Maximum allowed refresh token reuse exceeded"}"#,
+        );
+        let ret = do_refresh(&path, info(), ProviderType::TokenExchange).unwrap_err();
+        assert!(matches!(ret.downcast_ref::<CredmonError>(), Some(CredmonError::RefreshTokenRevoked(x)) if x.contains("reuse")));
+        assert_eq!(read_back().refresh_token, Some("rt2".into()));
+
+        // other errors are not
+        server.route("/token", 400, r#"{"error":"invalid_client"}"#);
+        let ret = do_refresh(&path, info(), ProviderType::TokenExchange).unwrap_err();
+        assert!(matches!(ret.downcast_ref::<CredmonError>(), Some(CredmonError::RequestError(_))));
+    }
+
+    #[test]
+    fn test_do_refresh_profiles() {
+        test_logger();
+        let server = TestServer::issuer(200, r#"{"access_token":"at2","refresh_token":"rt2","token_type":"bearer","expires_in":300}"#);
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("provider.top");
+        let profiles = vec![
+            AccessProfile {
+                name: "read".into(),
+                scopes: "storage.read:/".into(),
+                audience: None,
+                file: "provider_read.use".into(),
+            },
+            AccessProfile {
+                name: "write".into(),
+                scopes: "storage.modify:/out".into(),
+                audience: Some("https://storage".into()),
+                file: "provider_write.use".into(),
+            },
+        ];
+        RefreshFile {
+            refresh_token: Some("rt1".into()),
+            scopes: "storage.read:/ storage.modify:/out".into(),
+            profiles: profiles.clone(),
+            ..Default::default()
+        }
+        .write_to_file(&path)
+        .unwrap();
+
+        let info = client_info(&server);
+        let config = Config::new();
+        assert!(should_refresh_with_config(&path, &config).unwrap());
+        do_refresh(&path, info, ProviderType::TokenExchange).unwrap();
+
+        let requests = server.requests_to("/token");
+        assert_eq!(requests.len(), 3);
+        assert!(requests[0].body.contains("refresh_token=rt1"));
+        assert!(!requests[0].body.contains("scope="));
+        // the rotated refresh token is used for the profiles
+        assert!(requests[1].body.contains("refresh_token=rt2"));
+        assert!(requests[1].body.contains("scope=storage.read%3A%2F"));
+        assert!(!requests[1].body.contains("audience"));
+        assert!(requests[2].body.contains("scope=storage.modify%3A%2Fout"));
+        assert!(requests[2].body.contains("audience=https%3A%2F%2Fstorage"));
+
+        assert_eq!(RefreshFile::from_file(&path).unwrap().profiles, profiles);
+        let access = AccessFile::from_file(tmp_dir.path().join("provider_write.use")).unwrap();
+        assert_eq!(access.access_token, "at2");
+        assert_eq!(access.scope, vec!["storage.modify:/out".to_string()]);
+        assert!(!should_refresh_with_config(&path, &config).unwrap());
+
+        // a missing profile token means refreshing them all
+        fs::remove_file(tmp_dir.path().join("provider_read.use")).unwrap();
+        assert!(should_refresh_with_config(&path, &config).unwrap());
+    }
+
+    #[test]
+    fn test_do_refresh_profile_problems() {
+        test_logger();
+        let server = TestServer::issuer(200, r#"{"access_token":"at2","token_type":"bearer","expires_in":300}"#);
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("provider.top");
+        let profile = |name: &str| AccessProfile {
+            name: name.into(),
+            scopes: String::new(),
+            audience: None,
+            file: format!("provider_{name}.use"),
+        };
+        RefreshFile {
+            refresh_token: Some("rt1".into()),
+            scopes: "read".into(),
+            profiles: vec![profile("read"), profile("broken"), profile("write")],
+            ..Default::default()
+        }
+        .write_to_file(&path)
+        .unwrap();
+
+        // provider_read.use belongs to the credential with handle "read"
+        RefreshFile {
+            refresh_token: Some("other".into()),
+            ..Default::default()
+        }
+        .write_to_file(tmp_dir.path().join("provider_read.top"))
+        .unwrap();
+        // and the broken profile can't be written
+        fs::create_dir(tmp_dir.path().join("provider_broken.use")).unwrap();
+
+        let info = client_info(&server);
+        do_refresh(&path, info, ProviderType::TokenExchange).unwrap();
+
+        // main, broken and write, but not read
+        assert_eq!(server.requests_to("/token").len(), 3);
+        assert!(!tmp_dir.path().join("provider_read.use").exists());
+        assert!(tmp_dir.path().join("provider.use").exists());
+        assert!(tmp_dir.path().join("provider_write.use").exists());
+    }
 
     #[test]
     fn test_is_access_expired() {
@@ -152,4 +1044,197 @@ mod tests {
         assert!(!is_access_expired(path, 5));
         assert!(is_access_expired(path, 20));
     }
+
+    #[test]
+    fn test_lock_credential() {
+        test_logger();
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("user1").join("provider.top");
+
+        let lock = lock_credential(&path).unwrap();
+        // another process, or another thread here, has to wait
+        let other = File::open(path.with_extension("lock")).unwrap();
+        let other = Flock::lock(other, FlockArg::LockExclusiveNonblock).unwrap_err().0;
+        drop(lock);
+        Flock::lock(other, FlockArg::LockExclusiveNonblock).unwrap();
+    }
+
+    #[test]
+    fn test_split_provider() {
+        test_logger();
+        let mut config = Config::new();
+        config.insert("my_provider_ISSUER".into(), "https://foo".into());
+
+        assert_eq!(split_provider("my_provider", &config), ("my_provider".into(), None));
+        assert_eq!(split_provider("my_provider_handle", &config), ("my_provider".into(), Some("handle".into())));
+        assert_eq!(split_provider("other_handle", &config), ("other_handle".into(), None));
+    }
+
+    #[test]
+    fn test_split_provider_vault() {
+        test_logger();
+        let tmp_dir = tempdir().unwrap();
+        let mut config = Config::new();
+        config.insert("vault_PROVIDER_TYPE".into(), "VAULT".into());
+        config.insert("vault_VAULT_SERVER".into(), "https://vault.example.org:8200".into());
+        assert_eq!(split_provider("vault_handle", &config), ("vault".into(), Some("handle".into())));
+
+        // a handled vault credential is planned as a vault credential
+        let path = tmp_dir.path().join("vault_handle.top");
+        VaultFile {
+            vault_token: "hvs.token".into(),
+            vault_url: "https://vault.example.org:8200/v1/secrets/oauth/creds/vault/alice:handle".into(),
+            scopes: None,
+            audience: None,
+            refresh_expires_at: None,
+            other: Default::default(),
+        }
+        .write_to_file(&path)
+        .unwrap();
+        assert_eq!(unregistered_provider(&path, &config), Some(("vault".into(), ProviderType::Vault)));
+        assert_eq!(plan_refresh(&path, &config, SystemTime::now()), PlannedAction::Refresh);
+    }
+
+    #[test]
+    fn test_list_credentials() {
+        test_logger();
+        let tmp_dir = tempdir().unwrap();
+        let user_dir = tmp_dir.path().join("user1");
+        fs::create_dir(&user_dir).unwrap();
+        fs::write(user_dir.join("a.top"), "{}").unwrap();
+        fs::write(user_dir.join("a.use"), "{}").unwrap();
+        fs::write(user_dir.join("b_handle.top"), "{}").unwrap();
+        fs::write(tmp_dir.path().join("user1.mark"), "").unwrap();
+        fs::create_dir(tmp_dir.path().join("user2")).unwrap();
+
+        let ret = list_credentials(tmp_dir.path()).unwrap();
+        assert_eq!(ret, vec![user_dir.join("a.top"), user_dir.join("b_handle.top")]);
+    }
+
+    #[test]
+    fn test_list_marked_users() {
+        test_logger();
+        let tmp_dir = tempdir().unwrap();
+        fs::create_dir(tmp_dir.path().join("user1")).unwrap();
+        fs::write(mark_file(tmp_dir.path(), "user1"), "").unwrap();
+        fs::write(mark_file(tmp_dir.path(), "user2"), "").unwrap();
+        fs::write(tmp_dir.path().join("other"), "").unwrap();
+        fs::write(tmp_dir.path().join(".mark"), "").unwrap();
+        fs::write(tmp_dir.path().join("..mark"), "").unwrap();
+        fs::write(tmp_dir.path().join("...mark"), "").unwrap();
+
+        let mut config = Config::new();
+        config.insert("SEC_CREDENTIAL_SWEEP_DELAY".into(), 10.into());
+
+        let ret = list_marked_users(tmp_dir.path(), &config).unwrap();
+        assert_eq!(ret.len(), 2);
+        assert_eq!(ret[0].user, "user1");
+        assert_eq!(ret[1].user, "user2");
+        assert_eq!(ret[0].sweep_at.duration_since(ret[0].marked_at).unwrap(), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_plan_refresh_config_problem() {
+        test_logger();
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("provider.top");
+        let config = Config::new();
+        let now = SystemTime::now();
+
+        let ret = plan_refresh(&path, &config, now);
+        assert!(matches!(ret, PlannedAction::ConfigProblem(x) if x.contains("refresh token file")));
+
+        RefreshFile {
+            refresh_token: Some("foo".into()),
+            ..Default::default()
+        }
+        .write_to_file(&path)
+        .unwrap();
+        let ret = plan_refresh(&path, &config, now);
+        assert!(matches!(ret, PlannedAction::ConfigProblem(x) if x.contains("provider_ISSUER")));
+    }
+
+    #[test]
+    fn test_notify_expiring() {
+        test_logger();
+        let tmp_dir = tempdir().unwrap();
+        let user_dir = tmp_dir.path().join("user1");
+        fs::create_dir(&user_dir).unwrap();
+        let now = SystemTime::now();
+        let secs = now.duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+        for (name, refresh_expires_at) in [
+            ("a", Some(secs - 10.0)),
+            ("b", Some(secs + 86400.0)),
+            ("c", Some(secs + 30.0 * 86400.0)),
+            ("d", None),
+        ] {
+            RefreshFile {
+                refresh_token: Some("rt".into()),
+                refresh_expires_at,
+                ..Default::default()
+            }
+            .write_to_file(user_dir.join(format!("{name}.top")))
+            .unwrap();
+        }
+        assert!(is_refresh_token_expired(&user_dir.join("a.top"), now));
+        assert!(!is_refresh_token_expired(&user_dir.join("b.top"), now));
+        assert!(!is_refresh_token_expired(&user_dir.join("d.top"), now));
+
+        let ret = list_expiring(tmp_dir.path(), REFRESH_EXPIRY_WARNING, now).unwrap();
+        let paths: Vec<&PathBuf> = ret.iter().map(|x| &x.path).collect();
+        assert_eq!(paths, vec![&user_dir.join("a.top"), &user_dir.join("b.top")]);
+
+        let log = tmp_dir.path().join("hook.log");
+        let hook = tmp_dir.path().join("hook.sh");
+        fs::write(&hook, format!("echo $1 $2 >> {}\n", log.to_str().unwrap())).unwrap();
+        let mut config = Config::new();
+        config.insert("CREDMON_OAUTH_REFRESH_EXPIRY_HOOK".into(), format!("/bin/sh {}", hook.to_str().unwrap()).into());
+        let mut scheduler = RefreshScheduler::new(&config).unwrap();
+        assert_eq!(notify_expiring(tmp_dir.path(), &config, &mut scheduler, now).unwrap(), 2);
+        assert_eq!(fs::read_to_string(&log).unwrap(), "user1 a\nuser1 b\n");
+
+        // only once per refresh token
+        notify_expiring(tmp_dir.path(), &config, &mut scheduler, now).unwrap();
+        assert_eq!(fs::read_to_string(&log).unwrap().lines().count(), 2);
+
+        // a hook that doesn't finish is killed
+        fs::write(&hook, "sleep 30\n").unwrap();
+        let start = Instant::now();
+        let ret = run_expiry_hook(&format!("/bin/sh {}", hook.to_str().unwrap()), &ret[0], Duration::from_secs(1));
+        assert!(ret.unwrap_err().to_string().contains("did not finish"));
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_sweep_marked_users() {
+        test_logger();
+        let tmp_dir = tempdir().unwrap();
+        for user in ["user1", "user2", "user3"] {
+            fs::create_dir(tmp_dir.path().join(user)).unwrap();
+            fs::write(tmp_dir.path().join(user).join("a.top"), "{}").unwrap();
+        }
+        fs::write(mark_file(tmp_dir.path(), "user1"), "").unwrap();
+        fs::write(mark_file(tmp_dir.path(), "user2"), "").unwrap();
+
+        let mut config = Config::new();
+        config.insert("SEC_CREDENTIAL_SWEEP_DELAY".into(), 100.into());
+
+        // nothing is due yet
+        let ret = sweep_marked_users(tmp_dir.path(), &config, SystemTime::now()).unwrap();
+        assert!(ret.is_empty());
+
+        let later = SystemTime::now() + Duration::from_secs(200);
+        let ret = sweep_marked_users(tmp_dir.path(), &config, later).unwrap();
+        assert_eq!(ret, vec!["user1".to_string(), "user2".to_string()]);
+        assert!(!tmp_dir.path().join("user1").exists());
+        assert!(!mark_file(tmp_dir.path(), "user1").exists());
+        assert!(!tmp_dir.path().join("user2").exists());
+        assert!(tmp_dir.path().join("user3").exists());
+
+        // a bogus mark file sweeps nothing
+        fs::write(tmp_dir.path().join("..mark"), "").unwrap();
+        let ret = sweep_marked_users(tmp_dir.path(), &config, later).unwrap();
+        assert!(ret.is_empty());
+        assert!(tmp_dir.path().join("user3").exists());
+    }
 }
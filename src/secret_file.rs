@@ -0,0 +1,152 @@
+//! Reading client secrets and private keys from root-only files.
+
+use nix::unistd::geteuid;
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use zeroize::Zeroizing;
+
+use crate::config::{Config, coerce_to_bool};
+use crate::error::CredmonError;
+
+struct CachedSecret {
+    modified: SystemTime,
+    contents: Zeroizing<Vec<u8>>,
+}
+
+/// Secrets already read, by path. They are re-read when the file changes.
+static SECRET_CACHE: Mutex<BTreeMap<PathBuf, CachedSecret>> = Mutex::new(BTreeMap::new());
+
+/// Check that only root (or whoever we are running as) can read a secret file.
+pub fn check_permissions(path: &Path, metadata: &fs::Metadata) -> Result<(), CredmonError> {
+    let name = path.to_str().unwrap_or_default();
+    if metadata.uid() != 0 && metadata.uid() != geteuid().as_raw() {
+        return Err(CredmonError::ConfigError(format!("{name} is owned by uid {}, not root", metadata.uid())));
+    }
+    if metadata.mode() & 0o077 != 0 {
+        return Err(CredmonError::ConfigError(format!(
+            "{name} is accessible by group or others (mode {:o})",
+            metadata.mode() & 0o777
+        )));
+    }
+    Ok(())
+}
+
+fn allow_insecure(config: &Config) -> Result<bool, Box<dyn std::error::Error>> {
+    match config.get("CREDMON_OAUTH_ALLOW_INSECURE_SECRET_FILES") {
+        Some(x) => coerce_to_bool(x),
+        None => Ok(false),
+    }
+}
+
+/// Read a secret file, refusing files others can read.
+///
+/// Set `CREDMON_OAUTH_ALLOW_INSECURE_SECRET_FILES` to only warn instead.
+pub fn read_secret(path: &Path, config: &Config) -> Result<Zeroizing<Vec<u8>>, Box<dyn std::error::Error>> {
+    let metadata = fs::metadata(path)?;
+    if let Err(e) = check_permissions(path, &metadata) {
+        if !allow_insecure(config)? {
+            return Err(Box::new(e));
+        }
+        log::warn!("Insecure secret file: {e}");
+    }
+
+    let modified = metadata.modified()?;
+    let mut cache = SECRET_CACHE.lock().unwrap();
+    if let Some(cached) = cache.get(path)
+        && cached.modified == modified
+    {
+        return Ok(cached.contents.clone());
+    }
+
+    log::info!("Reading secret file {}", path.to_str().unwrap_or_default());
+    let contents = Zeroizing::new(fs::read(path)?);
+    cache.insert(
+        path.to_path_buf(),
+        CachedSecret {
+            modified,
+            contents: contents.clone(),
+        },
+    );
+    Ok(contents)
+}
+
+/// Read a secret file as a string, without surrounding whitespace.
+pub fn read_secret_string(path: &Path, config: &Config) -> Result<Zeroizing<String>, Box<dyn std::error::Error>> {
+    let contents = read_secret(path, config)?;
+    let s = std::str::from_utf8(&contents).map_err(|_| CredmonError::ConfigError(format!("{} is not utf-8", path.to_str().unwrap_or_default())))?;
+    Ok(Zeroizing::new(s.trim().to_string()))
+}
+
+/// Forget all cached secrets, so they are read again.
+pub fn flush_secret_cache() {
+    SECRET_CACHE.lock().unwrap().clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Duration;
+    use tempfile::NamedTempFile;
+
+    use crate::logging::test_logger;
+
+    #[test]
+    fn test_read_secret_string() {
+        test_logger();
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b"  secret\n\n").unwrap();
+        let config = Config::new();
+
+        let ret = read_secret_string(file.path(), &config).unwrap();
+        assert_eq!(ret.as_str(), "secret");
+    }
+
+    #[test]
+    fn test_insecure_permissions() {
+        test_logger();
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b"secret").unwrap();
+        fs::set_permissions(file.path(), fs::Permissions::from_mode(0o644)).unwrap();
+        let mut config = Config::new();
+
+        let ret = read_secret(file.path(), &config);
+        assert!(ret.err().unwrap().to_string().contains("group or others"));
+
+        config.insert("CREDMON_OAUTH_ALLOW_INSECURE_SECRET_FILES".into(), "true".into());
+        assert!(read_secret(file.path(), &config).is_ok());
+    }
+
+    #[test]
+    fn test_cache() {
+        test_logger();
+        let file = NamedTempFile::new().unwrap();
+        let config = Config::new();
+        let start = SystemTime::now() - Duration::from_secs(100);
+
+        fs::write(file.path(), "one").unwrap();
+        file.as_file().set_modified(start).unwrap();
+        assert_eq!(read_secret_string(file.path(), &config).unwrap().as_str(), "one");
+
+        // same mtime, so the cached secret is used
+        fs::write(file.path(), "two").unwrap();
+        file.as_file().set_modified(start).unwrap();
+        assert_eq!(read_secret_string(file.path(), &config).unwrap().as_str(), "one");
+
+        // a new mtime is noticed
+        file.as_file().set_modified(start + Duration::from_secs(1)).unwrap();
+        assert_eq!(read_secret_string(file.path(), &config).unwrap().as_str(), "two");
+
+        // flushing forces a re-read
+        fs::write(file.path(), "three").unwrap();
+        file.as_file().set_modified(start + Duration::from_secs(1)).unwrap();
+        assert_eq!(read_secret_string(file.path(), &config).unwrap().as_str(), "two");
+        flush_secret_cache();
+        assert_eq!(read_secret_string(file.path(), &config).unwrap().as_str(), "three");
+    }
+}
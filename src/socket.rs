@@ -0,0 +1,346 @@
+//! A Unix socket the credmon serves storer requests on, so the storer
+//! client does not need to be setuid.
+//!
+//! The client sends one JSON line with the argument condor gave it, and the
+//! credmon answers with one JSON line. The caller is identified with
+//! `SO_PEERCRED`, so users can only store credentials for themselves.
+
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+use nix::sys::stat::{Mode, umask};
+use nix::unistd::{Uid, User, geteuid};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::{Config, config as condor_config};
+use crate::data::Args;
+use crate::error::CredmonError;
+use crate::store::store_credential;
+
+/// How long the credmon waits for a client to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a client waits for the credmon to get tokens.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(120);
+/// Longest request line the credmon reads.
+const MAX_REQUEST_BYTES: u64 = 64 * 1024;
+/// How many connections one uid may have open at once.
+const MAX_CONNECTIONS_PER_USER: usize = 4;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SocketRequest {
+    /// The storer argument, like `options=provider&scopes=a,b`.
+    pub request: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SocketResponse {
+    pub ok: bool,
+    pub message: String,
+}
+
+/// The socket path, if `CREDMON_OAUTH_SOCKET` is set.
+pub fn socket_path(config: &Config) -> Result<Option<PathBuf>, CredmonError> {
+    match config.get("CREDMON_OAUTH_SOCKET") {
+        None => Ok(None),
+        Some(x) => match x.as_str() {
+            Some("") => Ok(None),
+            Some(x) => Ok(Some(PathBuf::from(x))),
+            None => Err(CredmonError::ConfigError("CREDMON_OAUTH_SOCKET is not a string".into())),
+        },
+    }
+}
+
+/// Store a credential for whoever is on the other end of the socket.
+fn store_for_peer(uid: u32, args: &Args) -> Result<String, Box<dyn Error>> {
+    if uid == 0 {
+        return Err(Box::new(CredmonError::GenericError("refusing to store credentials for root".into())));
+    }
+    let username = User::from_uid(Uid::from_raw(uid))?
+        .ok_or(CredmonError::GenericError(format!("no user for uid {uid}")))?
+        .name;
+    match store_credential(args, &username, &condor_config())? {
+        true => Ok("stored new tokens".into()),
+        false => Ok("token already exists".into()),
+    }
+}
+
+fn write_response(stream: &UnixStream, response: &SocketResponse) -> Result<(), Box<dyn Error>> {
+    let mut stream = stream;
+    stream.write_all(format!("{}\n", serde_json::to_string(response)?).as_bytes())?;
+    Ok(())
+}
+
+/// Read one request line, refusing to read more than `MAX_REQUEST_BYTES`.
+fn read_request(stream: &UnixStream) -> Result<SocketRequest, Box<dyn Error>> {
+    let mut line = String::new();
+    BufReader::new(stream.take(MAX_REQUEST_BYTES)).read_line(&mut line)?;
+    if !line.ends_with('\n') && line.len() as u64 >= MAX_REQUEST_BYTES {
+        return Err(Box::new(CredmonError::ArgumentError(format!(
+            "request is longer than {MAX_REQUEST_BYTES} bytes"
+        ))));
+    }
+    Ok(serde_json::from_str(&line)?)
+}
+
+fn handle_connection<F>(stream: UnixStream, uid: u32, handler: &F) -> Result<(), Box<dyn Error>>
+where
+    F: Fn(u32, &Args) -> Result<String, Box<dyn Error>>,
+{
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let result = read_request(&stream).and_then(|x| Args::parse(&x.request)).and_then(|args| {
+        log::warn!("Storer request from uid {uid} for provider {}", args.provider);
+        handler(uid, &args)
+    });
+    let response = match result {
+        Ok(message) => SocketResponse { ok: true, message },
+        Err(e) => {
+            log::warn!("Storer request from uid {uid} failed: {e}");
+            SocketResponse {
+                ok: false,
+                message: e.to_string(),
+            }
+        }
+    };
+    write_response(&stream, &response)
+}
+
+/// Handle one accepted connection on its own thread, unless its uid
+/// already has too many open.
+fn spawn_connection<F>(stream: UnixStream, handler: &Arc<F>, open: &Arc<Mutex<HashMap<u32, usize>>>) -> Result<(), Box<dyn Error>>
+where
+    F: Fn(u32, &Args) -> Result<String, Box<dyn Error>> + Send + Sync + 'static,
+{
+    let uid = getsockopt(&stream, PeerCredentials)?.uid();
+    {
+        let mut open = open.lock().unwrap_or_else(|e| e.into_inner());
+        let count = open.entry(uid).or_default();
+        if *count >= MAX_CONNECTIONS_PER_USER {
+            log::warn!("Refusing storer connection from uid {uid}: too many open connections");
+            return write_response(
+                &stream,
+                &SocketResponse {
+                    ok: false,
+                    message: "too many open connections".into(),
+                },
+            );
+        }
+        *count += 1;
+    }
+
+    let handler = Arc::clone(handler);
+    let open = Arc::clone(open);
+    thread::spawn(move || {
+        if let Err(e) = handle_connection(stream, uid, handler.as_ref()) {
+            log::warn!("Error handling storer connection: {e}");
+        }
+        let mut open = open.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = open.get_mut(&uid) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&uid);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Check nobody but us can replace the socket, by requiring its
+/// directory to be ours and not writable by group or others.
+fn check_socket_dir(path: &Path) -> Result<(), CredmonError> {
+    let dir = match path.parent() {
+        Some(x) if !x.as_os_str().is_empty() => x,
+        _ => Path::new("."),
+    };
+    let metadata = fs::metadata(dir).map_err(|e| CredmonError::ConfigError(format!("cannot check socket directory: {e}")))?;
+    if metadata.uid() != geteuid().as_raw() || metadata.mode() & 0o022 != 0 {
+        return Err(CredmonError::ConfigError(format!(
+            "socket directory {} must be owned by uid {} and not writable by group or others",
+            dir.to_str().unwrap_or_default(),
+            geteuid()
+        )));
+    }
+    Ok(())
+}
+
+/// Listen on `path`, handling each request on its own thread with `handler`.
+fn listen<F>(path: &Path, handler: F) -> Result<thread::JoinHandle<()>, Box<dyn Error>>
+where
+    F: Fn(u32, &Args) -> Result<String, Box<dyn Error>> + Send + Sync + 'static,
+{
+    check_socket_dir(path)?;
+    match fs::symlink_metadata(path) {
+        Ok(x) if x.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(Box::new(CredmonError::ConfigError(format!(
+                "{} exists and is not a socket",
+                path.to_str().unwrap_or_default()
+            ))));
+        }
+        Err(_) => {}
+    }
+    // anyone may connect, the peer's uid decides what they can do; set
+    // by umask, since chmod by path could follow a swapped-in symlink
+    let old_umask = umask(Mode::from_bits_truncate(0o111));
+    let listener = UnixListener::bind(path);
+    umask(old_umask);
+    let listener = listener?;
+    log::warn!("Listening for storer requests on {}", path.to_str().unwrap_or_default());
+
+    let handler = Arc::new(handler);
+    let open = Arc::new(Mutex::new(HashMap::new()));
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = spawn_connection(stream, &handler, &open) {
+                        log::warn!("Error handling storer connection: {e}");
+                    }
+                }
+                Err(e) => log::warn!("Error accepting storer connection: {e}"),
+            }
+        }
+    }))
+}
+
+/// Serve storer requests on `path` in a background thread.
+pub fn serve(path: &Path) -> Result<(), Box<dyn Error>> {
+    listen(path, store_for_peer)?;
+    Ok(())
+}
+
+/// Send a storer request to the credmon.
+pub fn send_request(path: &Path, request: &str) -> Result<SocketResponse, Box<dyn Error>> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+    let request = SocketRequest { request: request.into() };
+    stream.write_all(format!("{}\n", serde_json::to_string(&request)?).as_bytes())?;
+
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::unistd::getuid;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+
+    use crate::logging::test_logger;
+
+    #[test]
+    fn test_socket_path() {
+        test_logger();
+        let mut config = Config::new();
+        assert_eq!(socket_path(&config).unwrap(), None);
+        config.insert("CREDMON_OAUTH_SOCKET".into(), "".into());
+        assert_eq!(socket_path(&config).unwrap(), None);
+        config.insert("CREDMON_OAUTH_SOCKET".into(), "/run/credmon.sock".into());
+        assert_eq!(socket_path(&config).unwrap(), Some(PathBuf::from("/run/credmon.sock")));
+    }
+
+    #[test]
+    fn test_request() {
+        test_logger();
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("credmon.sock");
+
+        listen(&path, |uid, args| {
+            if args.provider == "bad" {
+                return Err(Box::new(CredmonError::RequestError("issuer said no".into())));
+            }
+            Ok(format!("{uid} {} {}", args.provider, args.scopes))
+        })
+        .unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().mode() & 0o777, 0o666);
+
+        let ret = send_request(&path, "options=provider&scopes=a,b").unwrap();
+        assert!(ret.ok);
+        assert_eq!(ret.message, format!("{} provider a b", getuid().as_raw()));
+
+        let ret = send_request(&path, "options=bad").unwrap();
+        assert!(!ret.ok);
+        assert!(ret.message.contains("issuer said no"));
+
+        let ret = send_request(&path, "options=../etc").unwrap();
+        assert!(!ret.ok);
+        assert!(ret.message.contains("invalid provider"));
+    }
+
+    #[test]
+    fn test_listen_checks() {
+        test_logger();
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("credmon.sock");
+
+        // an old socket is replaced, anything else is left alone
+        listen(&path, |_, args| Ok(args.provider.clone())).unwrap();
+        listen(&path, |_, args| Ok(args.provider.clone())).unwrap();
+        let file = tmp_dir.path().join("file");
+        fs::write(&file, "keep").unwrap();
+        assert!(listen(&file, |_, args| Ok(args.provider.clone())).is_err());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "keep");
+
+        // nor is a directory others could write to
+        fs::set_permissions(tmp_dir.path(), fs::Permissions::from_mode(0o777)).unwrap();
+        let err = listen(&path, |_, args| Ok(args.provider.clone())).unwrap_err();
+        assert!(err.to_string().contains("not writable by group or others"));
+    }
+
+    #[test]
+    fn test_idle_connections() {
+        test_logger();
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("credmon.sock");
+        listen(&path, |_, args| Ok(args.provider.clone())).unwrap();
+
+        // a client that never sends anything doesn't hold up the next one
+        let _idle = UnixStream::connect(&path).unwrap();
+        let ret = send_request(&path, "options=provider").unwrap();
+        assert!(ret.ok);
+        assert_eq!(ret.message, "provider");
+
+        // but one uid can only keep so many connections open
+        let path = tmp_dir.path().join("busy.sock");
+        listen(&path, |_, args| Ok(args.provider.clone())).unwrap();
+        let _idle: Vec<UnixStream> = (0..MAX_CONNECTIONS_PER_USER).map(|_| UnixStream::connect(&path).unwrap()).collect();
+        let ret = send_request(&path, "options=provider").unwrap();
+        assert!(!ret.ok);
+        assert!(ret.message.contains("too many open connections"));
+    }
+
+    #[test]
+    fn test_oversized_request() {
+        test_logger();
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("credmon.sock");
+        listen(&path, |_, args| Ok(args.provider.clone())).unwrap();
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.set_read_timeout(Some(RESPONSE_TIMEOUT)).unwrap();
+        // the credmon stops reading at the limit, so the rest may not be sent
+        let _ = stream.write_all(&vec![b'a'; MAX_REQUEST_BYTES as usize + 1024]);
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line).unwrap();
+        let ret: SocketResponse = serde_json::from_str(&line).unwrap();
+        assert!(!ret.ok);
+        assert!(ret.message.contains("longer than"));
+    }
+
+    #[test]
+    fn test_refuse_root() {
+        test_logger();
+        let args = Args::parse("options=provider").unwrap();
+        assert!(store_for_peer(0, &args).unwrap_err().to_string().contains("root"));
+    }
+}
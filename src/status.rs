@@ -0,0 +1,246 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::config::Config;
+use crate::data::{AccessFile, RefreshFile, StateFile, to_epoch};
+use crate::refresh::{list_credentials, mark_file, split_provider, sweep_delay};
+
+/// Status of a single stored credential.
+///
+/// This never contains token secrets, so it is safe to print.
+#[derive(Serialize, Debug, Default)]
+pub struct CredentialStatus {
+    pub user: String,
+    pub provider: String,
+    pub handle: Option<String>,
+    pub scopes: Option<String>,
+    /// The issuer identity the credential was requested for.
+    pub subject: Option<String>,
+    /// Identity claims from the issuer's ID token.
+    pub id_token_claims: BTreeMap<String, Value>,
+    /// Files of the credential's extra access tokens.
+    pub profile_files: Vec<String>,
+    pub expires_at: Option<f64>,
+    /// When the refresh token expires, if the issuer said.
+    pub refresh_expires_at: Option<f64>,
+    pub last_success: Option<f64>,
+    pub last_error: Option<String>,
+    pub consecutive_failures: u32,
+    pub quarantined: bool,
+    pub marked_at: Option<f64>,
+    pub sweep_at: Option<f64>,
+    pub problems: Vec<String>,
+}
+
+impl CredentialStatus {
+    pub fn from_path(path: &Path, cred_dir: &Path, config: &Config) -> Self {
+        let mut ret = Self::default();
+
+        if let Some(user) = path.parent().and_then(|x| x.file_name()).and_then(|x| x.to_str()) {
+            ret.user = user.to_string();
+        }
+        let name = path.file_stem().and_then(|x| x.to_str()).unwrap_or_default();
+        (ret.provider, ret.handle) = split_provider(name, config);
+
+        match RefreshFile::from_file(path) {
+            Ok(x) => {
+                ret.scopes = Some(x.scopes);
+                ret.subject = x.subject;
+                ret.refresh_expires_at = x.refresh_expires_at;
+                ret.id_token_claims = x.id_token_claims;
+                ret.profile_files = x.profiles.into_iter().map(|x| x.file).collect();
+            }
+            Err(e) => ret.problems.push(format!("cannot read refresh token file: {e}")),
+        }
+        match AccessFile::from_file(path.with_extension("use")) {
+            Ok(x) => ret.expires_at = Some(x.expires_at),
+            Err(e) => ret.problems.push(format!("cannot read access token file: {e}")),
+        }
+        if let Ok(state) = StateFile::from_file(StateFile::path_for(path)) {
+            ret.last_success = state.last_success;
+            ret.last_error = state.last_error;
+            ret.consecutive_failures = state.consecutive_failures;
+            ret.quarantined = state.quarantined_at.is_some();
+        }
+
+        if let Ok(modified) = fs::metadata(mark_file(cred_dir, &ret.user)).and_then(|x| x.modified()) {
+            let marked_at = to_epoch(modified);
+            ret.marked_at = Some(marked_at);
+            match sweep_delay(config) {
+                Ok(delay) => ret.sweep_at = Some(marked_at + delay as f64),
+                Err(e) => ret.problems.push(format!("bad SEC_CREDENTIAL_SWEEP_DELAY: {e}")),
+            }
+        }
+
+        ret
+    }
+}
+
+/// Get the status of all credentials in the credential directory.
+pub fn collect_status(cred_dir: &Path, config: &Config) -> Result<Vec<CredentialStatus>, Box<dyn std::error::Error>> {
+    Ok(list_credentials(cred_dir)?
+        .iter()
+        .map(|path| CredentialStatus::from_path(path, cred_dir, config))
+        .collect())
+}
+
+/// Format a relative time in seconds as something like `1h5m`.
+pub fn format_duration(secs: f64) -> String {
+    let sign = if secs < 0.0 { "-" } else { "" };
+    let secs = secs.abs() as u64;
+    let (d, h, m, s) = (secs / 86400, (secs / 3600) % 24, (secs / 60) % 60, secs % 60);
+    if d > 0 {
+        format!("{sign}{d}d{h}h")
+    } else if h > 0 {
+        format!("{sign}{h}h{m}m")
+    } else if m > 0 {
+        format!("{sign}{m}m{s}s")
+    } else {
+        format!("{sign}{s}s")
+    }
+}
+
+/// Format credential status as a table, with times relative to `now`.
+pub fn format_table(statuses: &[CredentialStatus], now: SystemTime) -> String {
+    let now = to_epoch(now);
+    let header = [
+        "USER",
+        "PROVIDER",
+        "HANDLE",
+        "SCOPES",
+        "EXPIRES IN",
+        "REFRESH EXPIRES IN",
+        "LAST REFRESH",
+        "STATUS",
+        "SWEEP IN",
+    ]
+    .map(String::from);
+
+    let mut rows = vec![header.to_vec()];
+    for x in statuses {
+        let status = if x.quarantined {
+            format!("quarantined: {}", x.last_error.as_deref().unwrap_or_default())
+        } else if x.refresh_expires_at.is_some_and(|t| t <= now) {
+            "refresh token expired".into()
+        } else if let Some(e) = &x.last_error {
+            format!("failing ({}): {e}", x.consecutive_failures)
+        } else if !x.problems.is_empty() {
+            x.problems.join("; ")
+        } else {
+            "ok".into()
+        };
+        rows.push(vec![
+            x.user.clone(),
+            x.provider.clone(),
+            x.handle.clone().unwrap_or("-".into()),
+            x.scopes.clone().unwrap_or("-".into()),
+            x.expires_at.map_or("-".into(), |t| format_duration(t - now)),
+            x.refresh_expires_at.map_or("-".into(), |t| format_duration(t - now)),
+            x.last_success.map_or("-".into(), |t| format!("{} ago", format_duration(now - t))),
+            status,
+            x.sweep_at.map_or("-".into(), |t| format_duration(t - now)),
+        ]);
+    }
+
+    let mut widths = vec![0; rows[0].len()];
+    for row in &rows {
+        for (i, col) in row.iter().enumerate() {
+            widths[i] = widths[i].max(col.len());
+        }
+    }
+
+    let mut ret = String::new();
+    for row in rows {
+        let line: Vec<String> = row.iter().zip(&widths).map(|(col, w)| format!("{col:<w$}")).collect();
+        ret += line.join("  ").trim_end();
+        ret += "\n";
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    use crate::logging::test_logger;
+
+    #[test]
+    fn test_format_duration() {
+        test_logger();
+        assert_eq!(format_duration(5.0), "5s");
+        assert_eq!(format_duration(65.0), "1m5s");
+        assert_eq!(format_duration(3700.0), "1h1m");
+        assert_eq!(format_duration(90000.0), "1d1h");
+        assert_eq!(format_duration(-120.0), "-2m0s");
+    }
+
+    #[test]
+    fn test_collect_status() {
+        test_logger();
+        let tmp_dir = tempdir().unwrap();
+        let user_dir = tmp_dir.path().join("user1");
+        fs::create_dir(&user_dir).unwrap();
+        let now = SystemTime::now();
+
+        let refresh_path = user_dir.join("provider_handle.top");
+        RefreshFile {
+            refresh_token: Some("supersecret-refresh".into()),
+            scopes: "read write".into(),
+            subject: Some("user1@example.org".into()),
+            refresh_expires_at: Some(to_epoch(now + Duration::from_secs(86400))),
+            id_token_claims: BTreeMap::from([("preferred_username".into(), "user1".into())]),
+            ..Default::default()
+        }
+        .write_to_file(&refresh_path)
+        .unwrap();
+        AccessFile {
+            access_token: "supersecret-access".into(),
+            token_type: "bearer".into(),
+            expires_in: 600,
+            expires_at: to_epoch(now + Duration::from_secs(600)),
+            scope: vec!["read".into(), "write".into()],
+        }
+        .write_to_file(refresh_path.with_extension("use"))
+        .unwrap();
+        StateFile {
+            last_error: Some("RequestError: invalid_grant".into()),
+            consecutive_failures: 1,
+            ..Default::default()
+        }
+        .write_to_file(StateFile::path_for(&refresh_path))
+        .unwrap();
+        fs::write(mark_file(tmp_dir.path(), "user1"), "").unwrap();
+
+        let mut config = Config::new();
+        config.insert("provider_ISSUER".into(), "https://foo".into());
+        config.insert("SEC_CREDENTIAL_SWEEP_DELAY".into(), 100.into());
+
+        let ret = collect_status(tmp_dir.path(), &config).unwrap();
+        assert_eq!(ret.len(), 1);
+        let x = &ret[0];
+        assert_eq!(x.user, "user1");
+        assert_eq!(x.provider, "provider");
+        assert_eq!(x.handle, Some("handle".into()));
+        assert_eq!(x.scopes, Some("read write".into()));
+        assert_eq!(x.subject, Some("user1@example.org".into()));
+        assert!(x.refresh_expires_at.is_some());
+        assert_eq!(x.id_token_claims["preferred_username"], "user1");
+        assert_eq!(x.consecutive_failures, 1);
+        assert!(x.problems.is_empty());
+        assert_eq!(x.sweep_at.unwrap() - x.marked_at.unwrap(), 100.0);
+
+        let table = format_table(&ret, now);
+        assert!(table.contains("provider"));
+        assert!(table.contains("invalid_grant"));
+        assert!(table.contains("REFRESH EXPIRES IN"));
+        assert!(!table.contains("supersecret"));
+        let json = serde_json::to_string(&ret).unwrap();
+        assert!(!json.contains("supersecret"));
+    }
+}
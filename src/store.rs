@@ -0,0 +1,262 @@
+//! Storing a new credential for a user, as the storer client does.
+
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::config::{Config, get_str};
+use crate::data::{AccessProfile, Args, ClientInfo, ProviderType, RefreshFile, compare_scopes, write_access_token_to_file, write_tokens_to_file};
+use crate::error::CredmonError;
+use crate::exchange::{do_client_credentials, do_token_exchange, map_subject};
+use crate::local_issuer::LocalIssuer;
+use crate::privileges::with_privileges;
+use crate::refresh::{credential_dir, get_profile_tokens, lock_credential, should_refresh_with_config, token_minimum};
+use crate::vault::{VaultConfig, do_vault_refresh};
+
+/// Path of the refresh token file for a storer request.
+pub fn credential_path(args: &Args, username: &str, config: &Config) -> Result<PathBuf, Box<dyn Error>> {
+    let mut refresh_filename = args.provider.clone();
+    if let Some(ref handle) = args.handle {
+        refresh_filename += "_";
+        refresh_filename += handle;
+        log::warn!("Creating token for {username} with provider {} and handle {handle}", args.provider);
+    } else {
+        log::warn!("Creating token for {username} with provider {} and no handle", args.provider);
+    }
+    refresh_filename += ".top";
+
+    Ok(credential_dir(config)?.join(username).join(refresh_filename))
+}
+
+/// Check that `username` may store credentials of a service account provider.
+///
+/// These tokens are minted with the client's own credentials, not the
+/// user's, so only the users in `<provider>_ALLOWED_USERS` get them.
+fn check_allowed_user(provider: &str, username: &str, config: &Config) -> Result<(), CredmonError> {
+    let key = format!("{provider}_ALLOWED_USERS");
+    let allowed = get_str(config, &key)?.unwrap_or_default();
+    if !allowed.split([',', ' ']).any(|x| x == username) {
+        return Err(CredmonError::ArgumentError(format!("{username} is not in {key}")));
+    }
+    Ok(())
+}
+
+/// Refuse a handle whose access token file is a profile file of another credential.
+///
+/// Default profile files look like a credential with the profile name as
+/// handle, and the two would keep overwriting each other's access token.
+fn check_profile_collision(args: &Args, path: &Path, config: &Config) -> Result<(), Box<dyn Error>> {
+    if args.handle.is_none() {
+        return Ok(());
+    }
+    let file = path.with_extension("use");
+    let file = file.file_name().and_then(|x| x.to_str()).unwrap_or_default();
+
+    let mut taken: Vec<String> = AccessProfile::from_config(&args.provider, &args.provider, config)?
+        .into_iter()
+        .map(|x| x.file)
+        .collect();
+    // and what the user's credentials recorded when they were stored
+    let others = with_privileges(|| -> Vec<String> {
+        let Ok(entries) = fs::read_dir(path.parent().unwrap()) else {
+            return Vec::new();
+        };
+        entries
+            .filter_map(|x| x.ok())
+            .map(|x| x.path())
+            .filter(|x| x.extension().is_some_and(|x| x == "top") && x != path)
+            .filter_map(|x| RefreshFile::from_file(x).ok())
+            .flat_map(|x| x.profiles.into_iter().map(|x| x.file))
+            .collect()
+    })?;
+    taken.extend(others);
+
+    if taken.iter().any(|x| x == file) {
+        return Err(Box::new(CredmonError::ArgumentError(format!(
+            "handle {} would overwrite the access token profile file {file}",
+            args.handle.as_deref().unwrap_or_default()
+        ))));
+    }
+    Ok(())
+}
+
+/// Get and store tokens for `username`, unless a matching credential exists.
+///
+/// Returns true if new tokens were stored. Only reading client secrets and
+/// the user's credentials is done with privileges, if they were dropped.
+pub fn store_credential(args: &Args, username: &str, config: &Config) -> Result<bool, Box<dyn Error>> {
+    let path = credential_path(args, username, config)?;
+    let provider_type = ProviderType::from_config(&args.provider, config)?;
+    if provider_type == ProviderType::ClientCredentials {
+        check_allowed_user(&args.provider, username, config)?;
+    }
+    check_profile_collision(args, &path, config)?;
+    // held until the new tokens are written, so the credmon doesn't refresh in between
+    let _lock = with_privileges(|| lock_credential(&path))??;
+
+    // check if the token already exists and matches the request
+    let create_token = with_privileges(|| match RefreshFile::from_file(&path) {
+        Ok(rf) => {
+            if !compare_scopes(args.scopes.as_str(), rf.scopes.as_str()) {
+                log::info!("Scopes of existing token do not match. Making new token!");
+                true
+            } else {
+                // check access token and expiration
+                should_refresh_with_config(&path, config).unwrap_or(true)
+            }
+        }
+        Err(_) => true,
+    })?;
+
+    if !create_token {
+        log::warn!("Token already exists, not contacting server");
+        return Ok(false);
+    }
+
+    match provider_type {
+        ProviderType::TokenExchange => {
+            let info = with_privileges(|| ClientInfo::new(&args.provider, config))??;
+            let lifetime = info.access_token_lifetime;
+            let credential_name = path.file_stem().and_then(|x| x.to_str()).unwrap_or_default();
+            let profiles = AccessProfile::from_config(&args.provider, credential_name, config)?;
+            let subject = map_subject(&args.provider, username, config)?;
+            let result = do_token_exchange(args, info, &subject, config)?;
+            with_privileges(|| write_tokens_to_file(&path, result, Some(subject), profiles, lifetime))??;
+
+            // profile tokens need the refresh token, which only root can read
+            let info = with_privileges(|| ClientInfo::new(&args.provider, config))??;
+            with_privileges(|| get_profile_tokens(&path, &info))??;
+        }
+        ProviderType::ClientCredentials => {
+            let info = with_privileges(|| ClientInfo::new(&args.provider, config))??;
+            let lifetime = info.access_token_lifetime;
+            let result = do_client_credentials(info, &args.scopes)?;
+            with_privileges(|| -> Result<(), Box<dyn Error>> {
+                write_access_token_to_file(&path, &result, args.scopes.split_whitespace().map(String::from).collect(), lifetime)?;
+                // no refresh token, just remember the scopes for the credmon
+                RefreshFile {
+                    scopes: args.scopes.clone(),
+                    ..Default::default()
+                }
+                .write_to_file(&path)
+            })??;
+        }
+        ProviderType::LocalIssuer => {
+            // nothing to ask an issuer for, sign the first token right away,
+            // and only keep the credential if its scopes are allowed
+            with_privileges(|| -> Result<(), Box<dyn Error>> {
+                let issuer = LocalIssuer::from_config(&args.provider, config)?;
+                let result = issuer.issue(username, &args.scopes, SystemTime::now())?;
+                RefreshFile {
+                    scopes: args.scopes.clone(),
+                    subject: Some(username.to_string()),
+                    ..Default::default()
+                }
+                .write_to_file(&path)?;
+                write_access_token_to_file(&path, &result, Vec::new(), issuer.lifetime)
+            })??;
+        }
+        ProviderType::Vault => {
+            // read as the user, unless the credmon is storing for them
+            let vault_config = VaultConfig::from_config(&args.provider, config)?;
+            let vault_file = vault_config.read_htgettoken_file(args, username)?;
+            with_privileges(|| -> Result<(), Box<dyn Error>> {
+                vault_file.write_to_file(&path)?;
+                do_vault_refresh(&path, &vault_config, token_minimum(config)?)
+            })??;
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+
+    use crate::client_auth::tests::TEST_EC_KEY;
+    use crate::logging::test_logger;
+
+    #[test]
+    fn test_client_credentials_allowed_users() {
+        test_logger();
+        let tmp_dir = tempdir().unwrap();
+        let mut config = Config::new();
+        config.insert("SEC_CREDENTIAL_DIRECTORY_OAUTH".into(), tmp_dir.path().to_str().into());
+        config.insert("robot_PROVIDER_TYPE".into(), "CLIENT_CREDENTIALS".into());
+        let args = Args::parse("options=robot&scopes=compute.create").unwrap();
+
+        let e = store_credential(&args, "mallory", &config).unwrap_err();
+        assert!(e.to_string().contains("robot_ALLOWED_USERS"));
+
+        config.insert("robot_ALLOWED_USERS".into(), "alice, bob".into());
+        let e = store_credential(&args, "mallory", &config).unwrap_err();
+        assert!(e.to_string().contains("robot_ALLOWED_USERS"));
+        assert!(!tmp_dir.path().join("mallory").exists());
+
+        assert!(check_allowed_user("robot", "bob", &config).is_ok());
+    }
+
+    #[test]
+    fn test_profile_collision() {
+        test_logger();
+        let tmp_dir = tempdir().unwrap();
+        fs::create_dir(tmp_dir.path().join("alice")).unwrap();
+        let mut config = Config::new();
+        config.insert("SEC_CREDENTIAL_DIRECTORY_OAUTH".into(), tmp_dir.path().to_str().into());
+        config.insert("myprovider_ACCESS_PROFILES".into(), "read".into());
+
+        let args = Args::parse("options=myprovider&handle=read").unwrap();
+        let path = credential_path(&args, "alice", &config).unwrap();
+        let e = check_profile_collision(&args, &path, &config).unwrap_err();
+        assert!(e.to_string().contains("myprovider_read.use"));
+
+        let args = Args::parse("options=myprovider&handle=other").unwrap();
+        let path = credential_path(&args, "alice", &config).unwrap();
+        check_profile_collision(&args, &path, &config).unwrap();
+
+        // profiles recorded in an existing credential count too
+        RefreshFile {
+            profiles: vec![AccessProfile {
+                name: "out".into(),
+                scopes: String::new(),
+                audience: None,
+                file: "myprovider_other.use".into(),
+            }],
+            ..Default::default()
+        }
+        .write_to_file(tmp_dir.path().join("alice/myprovider.top"))
+        .unwrap();
+        assert!(check_profile_collision(&args, &path, &config).is_err());
+    }
+
+    #[test]
+    fn test_local_issuer_scopes() {
+        test_logger();
+        let tmp_dir = tempdir().unwrap();
+        let key_path = tmp_dir.path().join("issuer.pem");
+        fs::write(&key_path, TEST_EC_KEY).unwrap();
+        fs::set_permissions(&key_path, fs::Permissions::from_mode(0o600)).unwrap();
+        fs::create_dir(tmp_dir.path().join("alice")).unwrap();
+
+        let mut config = Config::new();
+        config.insert("SEC_CREDENTIAL_DIRECTORY_OAUTH".into(), tmp_dir.path().to_str().into());
+        config.insert("local_PROVIDER_TYPE".into(), "LOCAL_ISSUER".into());
+        config.insert("local_ISSUER".into(), "https://credmon.example.org".into());
+        config.insert("local_LOCAL_ISSUER_KEY_FILE".into(), key_path.to_str().into());
+        config.insert("local_LOCAL_ISSUER_SCOPES".into(), "storage.read:/home/{username}".into());
+        let path = tmp_dir.path().join("alice/local.top");
+
+        // scopes outside the template leave nothing behind for the credmon to trip over
+        let args = Args::parse("options=local&scopes=storage.read:/").unwrap();
+        assert!(store_credential(&args, "alice", &config).is_err());
+        assert!(!path.exists());
+
+        let args = Args::parse("options=local&scopes=storage.read:/home/alice").unwrap();
+        assert!(store_credential(&args, "alice", &config).unwrap());
+        assert_eq!(RefreshFile::from_file(&path).unwrap().scopes, "storage.read:/home/alice");
+        assert!(path.with_extension("use").exists());
+    }
+}
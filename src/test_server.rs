@@ -0,0 +1,125 @@
+//! A tiny HTTP server standing in for an issuer in tests.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// A request the server received.
+#[derive(Debug, Clone)]
+pub struct ReceivedRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+type Routes = HashMap<String, (u16, String)>;
+
+pub struct TestServer {
+    pub url: String,
+    routes: Arc<Mutex<Routes>>,
+    requests: Arc<Mutex<Vec<ReceivedRequest>>>,
+}
+
+impl TestServer {
+    /// Start a server with no routes. Unknown paths get a 404.
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let routes = Arc::new(Mutex::new(Routes::new()));
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let (served, received) = (routes.clone(), requests.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { break };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let mut parts = line.split_whitespace();
+                let method = parts.next().unwrap_or_default().to_string();
+                let path = parts.next().unwrap_or_default().to_string();
+
+                let mut headers = HashMap::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some((k, v)) = line.split_once(':') {
+                        headers.insert(k.trim().to_ascii_lowercase(), v.trim().to_string());
+                    }
+                }
+                let len: usize = headers.get("content-length").and_then(|x| x.parse().ok()).unwrap_or(0);
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body).unwrap();
+
+                let route = path.split('?').next().unwrap().to_string();
+                received.lock().unwrap().push(ReceivedRequest {
+                    method,
+                    path,
+                    headers,
+                    body: String::from_utf8_lossy(&body).to_string(),
+                });
+
+                let (status, body) = served.lock().unwrap().get(&route).cloned().unwrap_or((404, "{}".into()));
+                let response = format!(
+                    "HTTP/1.1 {status} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+
+        Self { url, routes, requests }
+    }
+
+    /// Start an OpenID Connect issuer with discovery, and a token endpoint
+    /// answering with `token_status` and `token_body`.
+    pub fn issuer(token_status: u16, token_body: &str) -> Self {
+        let server = Self::start();
+        let discovery = serde_json::json!({
+            "issuer": server.url,
+            "authorization_endpoint": format!("{}/auth", server.url),
+            "token_endpoint": format!("{}/token", server.url),
+            "jwks_uri": format!("{}/certs", server.url),
+            "response_types_supported": ["code"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": ["RS256"],
+        });
+        server.route("/.well-known/openid-configuration", 200, &discovery.to_string());
+        server.route("/certs", 200, r#"{"keys":[]}"#);
+        server.route("/token", token_status, token_body);
+        server
+    }
+
+    /// Add fields to the issuer's discovery document, replacing any
+    /// already there.
+    pub fn add_metadata(&self, extra: serde_json::Value) {
+        let mut routes = self.routes.lock().unwrap();
+        let (_, body) = routes.get_mut("/.well-known/openid-configuration").unwrap();
+        let mut discovery: serde_json::Map<String, serde_json::Value> = serde_json::from_str(body).unwrap();
+        discovery.extend(extra.as_object().unwrap().clone());
+        *body = serde_json::Value::Object(discovery).to_string();
+    }
+
+    /// Answer requests to `path` with `status` and `body`.
+    pub fn route(&self, path: &str, status: u16, body: &str) {
+        self.routes.lock().unwrap().insert(path.to_string(), (status, body.to_string()));
+    }
+
+    /// Requests received so far.
+    pub fn requests(&self) -> Vec<ReceivedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Requests received so far for a path (without query).
+    pub fn requests_to(&self, path: &str) -> Vec<ReceivedRequest> {
+        self.requests().into_iter().filter(|x| x.path.split('?').next() == Some(path)).collect()
+    }
+}
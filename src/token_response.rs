@@ -0,0 +1,75 @@
+//! Token endpoint responses, shared by every way of getting tokens.
+
+use oauth2::ExtraTokenFields;
+use oauth2::basic::BasicTokenType;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+use crate::jwt::unverified_claims;
+
+/// ID token claims recorded with a credential.
+const STORED_ID_TOKEN_CLAIMS: [&str; 4] = ["sub", "preferred_username", "email", "name"];
+
+/// Token response fields beyond the standard ones.
+///
+/// Issuers add their own fields (Keycloak sends `not-before-policy` and
+/// `session_state`), so everything is kept in `other`.
+#[derive(Deserialize, Debug, Serialize, Default, Clone)]
+pub struct TokenExtraFields {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issued_token_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_expires_in: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+impl ExtraTokenFields for TokenExtraFields {}
+
+impl TokenExtraFields {
+    /// Claims from the ID token worth recording, if there is one.
+    ///
+    /// The ID token is not verified, so these are for information only.
+    pub fn id_token_claims(&self) -> BTreeMap<String, Value> {
+        let claims = self.id_token.as_deref().and_then(unverified_claims).unwrap_or_default();
+        STORED_ID_TOKEN_CLAIMS
+            .iter()
+            .filter_map(|&name| Some((name.to_string(), claims.get(name)?.clone())))
+            .collect()
+    }
+}
+
+/// A token endpoint response, with any extra fields.
+pub type IssuerTokenResponse = oauth2::StandardTokenResponse<TokenExtraFields, BasicTokenType>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::jwt::tests::sign_token;
+    use crate::logging::test_logger;
+
+    #[test]
+    fn test_token_extra_fields() {
+        test_logger();
+        let id_token = sign_token(&serde_json::json!({"sub": "1234", "preferred_username": "alice", "nonce": "n"}));
+        let body = serde_json::json!({
+            "access_token": "at",
+            "token_type": "bearer",
+            "id_token": id_token,
+            "not-before-policy": 0,
+            "session_state": "abcd",
+        });
+        let ret: IssuerTokenResponse = serde_json::from_value(body).unwrap();
+        let extra = ret.extra_fields();
+        assert_eq!(extra.issued_token_type, None);
+        assert_eq!(extra.other["session_state"], "abcd");
+        assert_eq!(extra.other["not-before-policy"], 0);
+
+        let claims = extra.id_token_claims();
+        assert_eq!(claims.keys().collect::<Vec<_>>(), ["preferred_username", "sub"]);
+        assert_eq!(claims["preferred_username"], "alice");
+    }
+}
@@ -0,0 +1,460 @@
+//! Getting access tokens from a HashiCorp Vault server, with the vault
+//! tokens `htgettoken` leaves for users.
+//!
+//! Vault keeps the refresh tokens itself. The credmon only keeps the user's
+//! vault token, renews it, and asks Vault's OAuth secrets path for access tokens.
+
+use nix::fcntl::OFlag;
+use nix::unistd::User;
+use oauth2::basic::BasicTokenType;
+use oauth2::{AccessToken, Scope};
+use reqwest::blocking::Response;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Read, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::{Config, get_int, get_str};
+use crate::data::{Args, DEFAULT_ACCESS_TOKEN_LIFETIME, write_access_token_to_file};
+use crate::error::CredmonError;
+use crate::http_client::HttpClient;
+use crate::token_response::{IssuerTokenResponse, TokenExtraFields};
+
+/// Where htgettoken puts the vault token, by default.
+const VAULT_TOKEN_FILE: &str = "/tmp/vt_u{uid}";
+/// Renew vault tokens that expire within a day, by default.
+const VAULT_RENEW_BEFORE: u64 = 86400;
+
+/// The `.top` file of a Vault credential.
+///
+/// The field names match what `condor_vault_storer` sends to credd, so
+/// credentials stored either way work.
+#[derive(Serialize, Deserialize)]
+pub struct VaultFile {
+    pub vault_token: String,
+    /// The full URL of the user's OAuth secrets path.
+    pub vault_url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audience: Option<String>,
+    /// When the vault token expires, as of its last renewal. Named like
+    /// the refresh token expiry, so expiry warnings cover vault tokens too.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_expires_at: Option<f64>,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+impl VaultFile {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    /// Write the file, replacing any old one in a single step.
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("top.tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// The Vault server, which is everything before `/v1/` in the secrets URL.
+    pub fn server(&self) -> Result<&str, CredmonError> {
+        self.vault_url
+            .split_once("/v1/")
+            .map(|x| x.0)
+            .ok_or(CredmonError::ConfigError(format!("vault_url {} has no /v1/ path", self.vault_url)))
+    }
+
+    /// Check the secrets URL is on the configured server. The file is
+    /// written by the user, who must not be able to send their vault
+    /// token elsewhere with the credmon's help.
+    pub fn check_server(&self, vault_config: &VaultConfig) -> Result<(), CredmonError> {
+        let server = self.server()?;
+        if server != vault_config.server {
+            return Err(CredmonError::ConfigError(format!(
+                "vault_url server {server} is not the configured {}",
+                vault_config.server
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Settings for a `VAULT` provider.
+pub struct VaultConfig {
+    /// Like `https://vault.example.org:8200`.
+    pub server: String,
+    /// The secrets path, with `{username}` replaced by the unix username.
+    secrets_path: String,
+    /// The htgettoken vault token file, with `{uid}` replaced by the user's uid.
+    token_file: String,
+    audience: Option<String>,
+    renew_before: Duration,
+    pub access_token_lifetime: Duration,
+}
+
+impl VaultConfig {
+    /// Read the Vault settings for a provider.
+    ///
+    /// `<provider>_VAULT_SERVER` is required. The secrets path defaults to
+    /// htgettoken's, `secrets/oauth/creds/<provider>/{username}:default`.
+    pub fn from_config(provider_name: &str, config: &Config) -> Result<Self, Box<dyn Error>> {
+        let prefix = format!("{provider_name}_VAULT");
+        let server = get_str(config, &format!("{prefix}_SERVER"))?
+            .ok_or(CredmonError::ConfigError(format!("missing {prefix}_SERVER in config")))?
+            .trim_end_matches('/')
+            .to_string();
+        let secrets_path = match get_str(config, &format!("{prefix}_SECRETS_PATH"))? {
+            Some(x) => x.trim_matches('/').to_string(),
+            None => format!("secrets/oauth/creds/{provider_name}/{{username}}:default"),
+        };
+
+        Ok(Self {
+            server,
+            secrets_path,
+            token_file: get_str(config, &format!("{prefix}_TOKEN_FILE"))?.unwrap_or(VAULT_TOKEN_FILE).to_string(),
+            audience: get_str(config, &format!("{prefix}_AUDIENCE"))?.map(String::from),
+            renew_before: Duration::from_secs(get_int(config, &format!("{prefix}_RENEW_BEFORE"), VAULT_RENEW_BEFORE)?),
+            access_token_lifetime: Duration::from_secs(get_int(
+                config,
+                &format!("{provider_name}_ACCESS_TOKEN_LIFETIME"),
+                DEFAULT_ACCESS_TOKEN_LIFETIME.as_secs(),
+            )?),
+        })
+    }
+
+    /// The secrets URL for a user.
+    pub fn vault_url(&self, username: &str) -> String {
+        format!("{}/v1/{}", self.server, self.secrets_path.replace("{username}", username))
+    }
+
+    /// Read the vault token htgettoken stored for `username`.
+    ///
+    /// The file must belong to the user and not be readable by anyone else,
+    /// so one user can't plant a token for another.
+    pub fn read_htgettoken_file(&self, args: &Args, username: &str) -> Result<VaultFile, Box<dyn Error>> {
+        let user = User::from_name(username)?.ok_or(CredmonError::ArgumentError(format!("unknown user {username}")))?;
+        let path = self.token_file.replace("{uid}", &user.uid.to_string());
+        // checked and read through one handle, so the user can't swap in a
+        // symlink to someone else's token in between
+        let mut file = OpenOptions::new()
+            .read(true)
+            .custom_flags(OFlag::O_NOFOLLOW.bits())
+            .open(&path)
+            .map_err(|e| CredmonError::ArgumentError(format!("cannot read vault token {path}, run htgettoken first: {e}")))?;
+        let metadata = file.metadata()?;
+        if !metadata.is_file() || metadata.uid() != user.uid.as_raw() || metadata.mode() & 0o077 != 0 {
+            return Err(Box::new(CredmonError::ArgumentError(format!(
+                "vault token {path} must be a regular file owned by {username} and readable only by them"
+            ))));
+        }
+        let mut vault_token = String::new();
+        file.read_to_string(&mut vault_token)?;
+
+        Ok(VaultFile {
+            vault_token: vault_token.trim().to_string(),
+            vault_url: self.vault_url(username),
+            scopes: Some(args.scopes.clone()).filter(|x| !x.trim().is_empty()),
+            audience: self.audience.clone(),
+            refresh_expires_at: None,
+            other: Map::new(),
+        })
+    }
+}
+
+/// Convert an unsuccessful Vault response.
+///
+/// Vault answers 403 for expired or revoked tokens, which only a new
+/// htgettoken run can fix.
+fn vault_error(response: Response) -> CredmonError {
+    let status = response.status();
+    let message = format!("vault returned {status}: {}", response.text().unwrap_or_default());
+    match status.as_u16() {
+        403 => CredmonError::RefreshTokenRevoked(message),
+        n if n >= 500 => CredmonError::IssuerUnavailable(message),
+        _ => CredmonError::RequestError(message),
+    }
+}
+
+#[derive(Deserialize)]
+struct RenewAuth {
+    client_token: Option<String>,
+    lease_duration: u64,
+}
+
+#[derive(Deserialize)]
+struct RenewResponse {
+    auth: RenewAuth,
+}
+
+#[derive(Deserialize)]
+struct SecretData {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct SecretResponse {
+    data: SecretData,
+}
+
+/// Renew the vault token, if it expires within the renewal window.
+///
+/// Returns true if it was renewed.
+pub fn renew_if_needed(vault_file: &mut VaultFile, vault_config: &VaultConfig, http_client: &HttpClient, now: SystemTime) -> Result<bool, Box<dyn Error>> {
+    let now = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    if vault_file
+        .refresh_expires_at
+        .is_some_and(|t| t - now.as_secs_f64() > vault_config.renew_before.as_secs_f64())
+    {
+        return Ok(false);
+    }
+
+    let url = format!("{}/v1/auth/token/renew-self", vault_config.server);
+    log::info!("  POST {url}");
+    let response = http_client
+        .inner()
+        .post(&url)
+        .header("X-Vault-Token", &vault_file.vault_token)
+        .send()
+        .map_err(|e| CredmonError::IssuerUnavailable(e.to_string()))?;
+    log::info!("  response status: {}", response.status());
+    if !response.status().is_success() {
+        return Err(Box::new(vault_error(response)));
+    }
+
+    let renewed: RenewResponse = response
+        .json()
+        .map_err(|e| CredmonError::IssuerUnavailable(format!("cannot parse response: {e}")))?;
+    if let Some(token) = renewed.auth.client_token {
+        vault_file.vault_token = token;
+    }
+    vault_file.refresh_expires_at = Some(now.as_secs_f64() + renewed.auth.lease_duration as f64);
+    log::info!("  Renewed vault token for {} seconds", renewed.auth.lease_duration);
+    Ok(true)
+}
+
+/// Get an access token from the secrets path, valid for at least `minimum_seconds`.
+pub fn fetch_access_token(vault_file: &VaultFile, http_client: &HttpClient, minimum_seconds: u64) -> Result<IssuerTokenResponse, Box<dyn Error>> {
+    let mut params = vec![("minimum_seconds", minimum_seconds.to_string())];
+    if let Some(scopes) = &vault_file.scopes {
+        params.push(("scopes", scopes.split_whitespace().collect::<Vec<_>>().join(",")));
+    }
+    if let Some(audience) = &vault_file.audience {
+        params.push(("audience", audience.clone()));
+    }
+
+    log::info!("  GET {}", vault_file.vault_url);
+    let response = http_client
+        .inner()
+        .get(&vault_file.vault_url)
+        .query(&params)
+        .header("X-Vault-Token", &vault_file.vault_token)
+        .send()
+        .map_err(|e| CredmonError::IssuerUnavailable(e.to_string()))?;
+    log::info!("  response status: {}", response.status());
+    if !response.status().is_success() {
+        return Err(Box::new(vault_error(response)));
+    }
+
+    let secret: SecretResponse = response
+        .json()
+        .map_err(|e| CredmonError::IssuerUnavailable(format!("cannot parse response: {e}")))?;
+    let mut token = IssuerTokenResponse::new(AccessToken::new(secret.data.access_token), BasicTokenType::Bearer, TokenExtraFields::default());
+    token.set_scopes(vault_file.scopes.as_ref().map(|x| x.split_whitespace().map(|x| Scope::new(x.into())).collect()));
+    Ok(token)
+}
+
+/// Renew the vault token and get a new access token for the credential at `path`.
+pub fn do_vault_refresh(path: &Path, vault_config: &VaultConfig, minimum_seconds: u64) -> Result<(), Box<dyn Error>> {
+    log::warn!("  Now doing vault refresh for {}", path.to_str().unwrap());
+    let mut vault_file = VaultFile::from_file(path)?;
+    if let Err(e) = vault_file.check_server(vault_config) {
+        log::warn!("Refusing to refresh {}: {e}", path.to_str().unwrap());
+        return Err(Box::new(e));
+    }
+    let http_client = HttpClient::new()?;
+
+    if renew_if_needed(&mut vault_file, vault_config, &http_client, SystemTime::now())? {
+        vault_file.write_to_file(path)?;
+    }
+    let token = fetch_access_token(&vault_file, &http_client, minimum_seconds)?;
+    write_access_token_to_file(path, &token, Vec::new(), vault_config.access_token_lifetime)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::unistd::Uid;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+
+    use crate::data::{AccessFile, RefreshFile};
+    use crate::jwt::tests::sign_token;
+    use crate::logging::test_logger;
+    use crate::test_server::TestServer;
+
+    const SECRETS_PATH: &str = "/v1/secrets/oauth/creds/vault/alice:default";
+
+    fn test_config(server: &TestServer, token_file: &Path) -> Config {
+        let mut config = Config::new();
+        config.insert("vault_VAULT_SERVER".into(), format!("{}/", server.url).into());
+        config.insert("vault_VAULT_TOKEN_FILE".into(), token_file.to_str().into());
+        config
+    }
+
+    fn vault_file(server: &TestServer, refresh_expires_at: Option<f64>) -> VaultFile {
+        VaultFile {
+            vault_token: "hvs.old".into(),
+            vault_url: format!("{}{SECRETS_PATH}", server.url),
+            scopes: Some("storage.read:/ compute.create".into()),
+            audience: None,
+            refresh_expires_at,
+            other: Map::new(),
+        }
+    }
+
+    #[test]
+    fn test_vault_config() {
+        test_logger();
+        let server = TestServer::start();
+        let config = test_config(&server, Path::new("/tmp/vt_u{uid}"));
+        let vault_config = VaultConfig::from_config("vault", &config).unwrap();
+        assert_eq!(vault_config.vault_url("alice"), format!("{}{SECRETS_PATH}", server.url));
+        assert_eq!(vault_file(&server, None).server().unwrap(), server.url);
+
+        assert!(VaultConfig::from_config("other", &config).is_err());
+    }
+
+    #[test]
+    fn test_read_htgettoken_file() {
+        test_logger();
+        let tmp_dir = tempdir().unwrap();
+        let server = TestServer::start();
+        let username = User::from_uid(Uid::current()).unwrap().unwrap().name;
+        let token_path = tmp_dir.path().join("vt_u{uid}");
+        let config = test_config(&server, &token_path);
+        let vault_config = VaultConfig::from_config("vault", &config).unwrap();
+        let args = Args::parse("options=vault&scopes=storage.read:/").unwrap();
+
+        // htgettoken hasn't run yet
+        assert!(vault_config.read_htgettoken_file(&args, &username).is_err());
+
+        let real_path = tmp_dir.path().join(format!("vt_u{}", Uid::current()));
+        fs::write(&real_path, "hvs.token\n").unwrap();
+        fs::set_permissions(&real_path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(vault_config.read_htgettoken_file(&args, &username).is_err());
+
+        fs::set_permissions(&real_path, fs::Permissions::from_mode(0o600)).unwrap();
+        let vault_file = vault_config.read_htgettoken_file(&args, &username).unwrap();
+        assert_eq!(vault_file.vault_token, "hvs.token");
+        assert_eq!(vault_file.vault_url, vault_config.vault_url(&username));
+        assert_eq!(vault_file.scopes.as_deref(), Some("storage.read:/"));
+
+        // a symlink to another token is refused, even if the target looks fine
+        let other_path = tmp_dir.path().join("other_token");
+        fs::rename(&real_path, &other_path).unwrap();
+        std::os::unix::fs::symlink(&other_path, &real_path).unwrap();
+        assert!(vault_config.read_htgettoken_file(&args, &username).is_err());
+    }
+
+    #[test]
+    fn test_renew_if_needed() {
+        test_logger();
+        let server = TestServer::start();
+        server.route(
+            "/v1/auth/token/renew-self",
+            200,
+            r#"{"auth": {"client_token": "hvs.new", "lease_duration": 604800, "renewable": true}}"#,
+        );
+        let vault_config = VaultConfig::from_config("vault", &test_config(&server, Path::new("/nonexistent"))).unwrap();
+        let http_client = HttpClient::new().unwrap();
+        let now = SystemTime::now();
+        let now_secs = now.duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+
+        // not expiring soon
+        let mut file = vault_file(&server, Some(now_secs + 2.0 * VAULT_RENEW_BEFORE as f64));
+        assert!(!renew_if_needed(&mut file, &vault_config, &http_client, now).unwrap());
+        assert!(server.requests_to("/v1/auth/token/renew-self").is_empty());
+
+        let mut file = vault_file(&server, Some(now_secs + 60.0));
+        assert!(renew_if_needed(&mut file, &vault_config, &http_client, now).unwrap());
+        assert_eq!(file.vault_token, "hvs.new");
+        assert_eq!(file.refresh_expires_at, Some(now_secs + 604800.0));
+        let requests = server.requests_to("/v1/auth/token/renew-self");
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].headers["x-vault-token"], "hvs.old");
+
+        // expired or revoked vault tokens can't be fixed by retrying
+        server.route("/v1/auth/token/renew-self", 403, r#"{"errors": ["permission denied"]}"#);
+        let mut file = vault_file(&server, None);
+        let e = renew_if_needed(&mut file, &vault_config, &http_client, now).unwrap_err();
+        assert!(matches!(e.downcast_ref::<CredmonError>(), Some(CredmonError::RefreshTokenRevoked(_))));
+
+        server.route("/v1/auth/token/renew-self", 503, r#"{"errors": ["sealed"]}"#);
+        let e = renew_if_needed(&mut file, &vault_config, &http_client, now).unwrap_err();
+        assert!(matches!(e.downcast_ref::<CredmonError>(), Some(CredmonError::IssuerUnavailable(_))));
+    }
+
+    #[test]
+    fn test_do_vault_refresh() {
+        test_logger();
+        let tmp_dir = tempdir().unwrap();
+        let server = TestServer::start();
+        let exp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 1800;
+        let access_token = sign_token(&serde_json::json!({"sub": "alice", "exp": exp}));
+        server.route(
+            "/v1/auth/token/renew-self",
+            200,
+            r#"{"auth": {"client_token": "hvs.old", "lease_duration": 604800}}"#,
+        );
+        server.route(
+            SECRETS_PATH,
+            200,
+            &format!(r#"{{"data": {{"access_token": "{access_token}", "type": "Bearer"}}}}"#),
+        );
+        let vault_config = VaultConfig::from_config("vault", &test_config(&server, Path::new("/nonexistent"))).unwrap();
+
+        let path = tmp_dir.path().join("vault.top");
+        let mut file = vault_file(&server, None);
+        file.other.insert("from_storer".into(), "kept".into());
+        file.write_to_file(&path).unwrap();
+
+        do_vault_refresh(&path, &vault_config, 300).unwrap();
+
+        let access = AccessFile::from_file(path.with_extension("use")).unwrap();
+        assert_eq!(access.access_token, access_token);
+        assert_eq!(access.expires_at, exp as f64);
+        assert_eq!(access.scope, ["storage.read:/", "compute.create"]);
+
+        let requests = server.requests_to(SECRETS_PATH);
+        assert_eq!(requests[0].headers["x-vault-token"], "hvs.old");
+        assert!(requests[0].path.contains("minimum_seconds=300"));
+        assert!(requests[0].path.contains("scopes=storage.read%3A%2F%2Ccompute.create"));
+
+        // the renewal was recorded, and the rest of the file kept
+        let file = VaultFile::from_file(&path).unwrap();
+        assert!(file.refresh_expires_at.is_some());
+        assert_eq!(file.other["from_storer"], "kept");
+        // and it still reads as a refresh file, for status and expiry warnings
+        assert_eq!(RefreshFile::from_file(&path).unwrap().refresh_expires_at, file.refresh_expires_at);
+
+        // the user can't send the vault token to another server
+        let other = TestServer::start();
+        let mut file = vault_file(&server, None);
+        file.vault_url = format!("{}{SECRETS_PATH}", other.url);
+        file.write_to_file(&path).unwrap();
+        let e = do_vault_refresh(&path, &vault_config, 300).unwrap_err();
+        assert!(e.to_string().contains("is not the configured"));
+        assert!(other.requests().is_empty());
+    }
+}